@@ -0,0 +1,7 @@
+CREATE TYPE todo_state AS ENUM ('Opened', 'Closed');
+
+CREATE TABLE todos (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    text TEXT NOT NULL,
+    state todo_state NOT NULL
+);
@@ -0,0 +1,9 @@
+ALTER TABLE todos
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN closed_at TIMESTAMPTZ,
+    ADD COLUMN created_by TEXT,
+    ADD COLUMN updated_by TEXT;
+
+-- When closed todos were closed is unknown, the migration is the closest timestamp there is
+UPDATE todos SET closed_at = updated_at WHERE state = 'Closed';
@@ -0,0 +1,9 @@
+-- Todos created before ownership was introduced belong to nobody and are hidden from the api
+-- until they are assigned, e.g. UPDATE todos SET owner_id = '<user id>' WHERE owner_id = '';
+-- An empty tenant_id marks owners outside of any tenant.
+ALTER TABLE todos
+    ADD COLUMN owner_id TEXT NOT NULL DEFAULT '',
+    ADD COLUMN tenant_id TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX todos_owner_id_idx ON todos (tenant_id, owner_id, id);
+CREATE INDEX todos_owner_text_idx ON todos (tenant_id, owner_id, (text COLLATE "C"), id);
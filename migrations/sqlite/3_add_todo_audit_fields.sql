@@ -0,0 +1,13 @@
+-- SQLite only accepts constant defaults when adding columns, existing rows are stamped afterwards
+ALTER TABLE todos ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00Z';
+ALTER TABLE todos ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00Z';
+ALTER TABLE todos ADD COLUMN closed_at TEXT;
+ALTER TABLE todos ADD COLUMN created_by TEXT;
+ALTER TABLE todos ADD COLUMN updated_by TEXT;
+
+UPDATE todos SET
+    created_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+    updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now');
+
+-- When closed todos were closed is unknown, the migration is the closest timestamp there is
+UPDATE todos SET closed_at = updated_at WHERE state = 'Closed';
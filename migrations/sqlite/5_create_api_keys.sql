@@ -0,0 +1,13 @@
+-- API keys of service-to-service callers, only a salted sha256 hash of the secret is kept.
+-- Scopes are the comma separated roles granted to the key.
+CREATE TABLE api_keys (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    salt TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT,
+    revoked_at TEXT
+);
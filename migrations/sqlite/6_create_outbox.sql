@@ -0,0 +1,15 @@
+-- Domain events written in the same transaction as the todo change they describe.
+-- The relay publishes them in id order and stamps published_at, delivery is at-least-once.
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_type TEXT NOT NULL,
+    todo_id INTEGER NOT NULL,
+    tenant_id TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    occurred_at TEXT NOT NULL,
+    published_at TEXT,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT
+);
+
+CREATE INDEX outbox_pending_idx ON outbox (published_at, id);
@@ -0,0 +1,2 @@
+-- Tenant the requests made with a key are scoped to, NULL for callers outside of any tenant
+ALTER TABLE api_keys ADD COLUMN tenant_id TEXT;
@@ -0,0 +1,224 @@
+use axum::{
+    extract::{ConnectInfo, MatchedPath, State},
+    http::{
+        header::{CONTENT_LENGTH, USER_AGENT},
+        HeaderMap, Request, StatusCode,
+    },
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::HttpBody;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+use tracing::info;
+
+use crate::config::{AccessLogConfig, AccessLogField};
+use crate::metrics::UNMATCHED_ROUTE;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+pub struct AccessLog {
+    fields: Vec<AccessLogField>,
+    trusted_proxies: Vec<IpAddr>,
+    success_sample_rate: f64,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        Self {
+            fields: config.fields.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            success_sample_rate: config.success_sample_rate,
+        }
+    }
+
+    fn sampled(&self, status: StatusCode) -> bool {
+        status.is_client_error()
+            || status.is_server_error()
+            || rand::random::<f64>() < self.success_sample_rate
+    }
+
+    // Walks the X-Forwarded-For chain back from the closest hop, as long as the hops are
+    // trusted proxies. The header is ignored when the peer itself is not a trusted proxy.
+    fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = peer?;
+        let forwarded = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+
+        for hop in forwarded.iter().rev() {
+            if !self.trusted_proxies.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => client = hop,
+                Err(_) => break,
+            }
+        }
+
+        Some(client)
+    }
+}
+
+// Middleware writing an access log line per request, labelled by the route template.
+// Use with `from_fn_with_state` as a router layer inside of the request span, so that the
+// line carries the request id.
+pub async fn log_requests<B>(
+    State(access_log): State<Arc<AccessLog>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let started = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_owned());
+    let method = request.method().clone();
+    let bytes_in = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client_ip = access_log.client_ip(peer, request.headers());
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if !access_log.sampled(status) {
+        return response;
+    }
+    let bytes_out = response.body().size_hint().exact();
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    // Fields left out by the allow-list are None, which tracing does not record
+    let logs = |field| access_log.fields.contains(&field);
+    info!(
+        target: "access_log",
+        method = logs(AccessLogField::Method).then(|| display(&method)),
+        route = logs(AccessLogField::Route).then_some(route.as_str()),
+        status = logs(AccessLogField::Status).then_some(status.as_u16()),
+        latency_ms = logs(AccessLogField::Latency).then_some(latency_ms),
+        bytes_in = bytes_in.filter(|_| logs(AccessLogField::BytesIn)),
+        bytes_out = bytes_out.filter(|_| logs(AccessLogField::BytesOut)),
+        client_ip = client_ip.filter(|_| logs(AccessLogField::ClientIp)).map(display),
+        user_agent = user_agent.as_deref().filter(|_| logs(AccessLogField::UserAgent)),
+        "request completed"
+    );
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use serde_json::Value;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tower::ServiceExt;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn access_log(fields: Vec<AccessLogField>, success_sample_rate: f64) -> AccessLog {
+        AccessLog::new(&AccessLogConfig {
+            enabled: true,
+            fields,
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            success_sample_rate,
+        })
+    }
+
+    #[test]
+    fn client_ip_should_only_trust_forwarded_for_from_trusted_proxies() {
+        let access_log = access_log(vec![], 1.0);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            "198.51.100.1, 203.0.113.7, 10.0.0.2".parse().unwrap(),
+        );
+
+        let client = |peer: &str| access_log.client_ip(peer.parse().ok(), &headers);
+        assert_eq!(client("10.0.0.1"), "203.0.113.7".parse().ok());
+        assert_eq!(client("192.0.2.9"), "192.0.2.9".parse().ok());
+        assert_eq!(access_log.client_ip(None, &headers), None);
+    }
+
+    #[tokio::test]
+    async fn log_requests_should_log_allowed_fields_and_sample_successes() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        let fields = vec![
+            AccessLogField::Method,
+            AccessLogField::Status,
+            AccessLogField::ClientIp,
+        ];
+        let app = Router::new()
+            .route("/todos/:id", get(|| async { "todo" }))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(access_log(fields, 0.0)),
+                log_requests,
+            ));
+
+        for uri in ["/todos/7", "/missing"] {
+            let mut request = Request::builder()
+                .uri(uri)
+                .header(X_FORWARDED_FOR, "203.0.113.7")
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 1);
+        let fields = &lines[0]["fields"];
+        assert_eq!(lines[0]["target"], "access_log");
+        assert_eq!(fields["method"], "GET");
+        assert_eq!(fields["status"], 404);
+        assert_eq!(fields["client_ip"], "203.0.113.7");
+        assert!(fields.get("route").is_none());
+        assert!(fields.get("latency_ms").is_none());
+    }
+}
@@ -0,0 +1,253 @@
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqlitePool, FromRow};
+
+use super::Principal;
+use crate::error::Error;
+use crate::model::Role;
+
+// Keys are handed out as `<id>.<secret>`, the id is public and used to look the key up
+const ID_LENGTH: usize = 16;
+const SECRET_BYTES: usize = 32;
+const SALT_BYTES: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub subject: String,
+    pub tenant_id: Option<String>,
+    pub scopes: Vec<Role>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromRow)]
+struct ApiKeyRow {
+    id: String,
+    name: String,
+    subject: String,
+    tenant_id: Option<String>,
+    scopes: String,
+    salt: String,
+    hash: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            subject: row.subject,
+            tenant_id: row.tenant_id,
+            scopes: row
+                .scopes
+                .split(',')
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            revoked_at: row.revoked_at,
+        }
+    }
+}
+
+const API_KEY_COLUMNS: &str =
+    "id, name, subject, tenant_id, scopes, salt, hash, created_at, expires_at, revoked_at";
+
+pub struct ApiKeyStore {
+    pool: SqlitePool,
+}
+
+impl ApiKeyStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    // Returns the stored key along with the full key, which can not be recovered later on
+    pub async fn create(
+        &self,
+        name: &str,
+        subject: &str,
+        tenant_id: Option<&str>,
+        scopes: &[Role],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiKey, String), Error> {
+        let mut rng = rand::thread_rng();
+        let id: String = (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(ID_LENGTH)
+            .map(char::from)
+            .collect();
+        let mut secret = [0u8; SECRET_BYTES];
+        rng.fill_bytes(&mut secret);
+        let secret = hex::encode(secret);
+        let mut salt = [0u8; SALT_BYTES];
+        rng.fill_bytes(&mut salt);
+
+        let scopes = scopes
+            .iter()
+            .map(Role::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let row = sqlx::query_as::<_, ApiKeyRow>(&format!(
+            "insert into api_keys \
+             (id, name, subject, tenant_id, scopes, salt, hash, created_at, expires_at) \
+             values (?, ?, ?, ?, ?, ?, ?, ?, ?) returning {}",
+            API_KEY_COLUMNS
+        ))
+        .bind(&id)
+        .bind(name)
+        .bind(subject)
+        .bind(tenant_id)
+        .bind(scopes)
+        .bind(hex::encode(salt))
+        .bind(hex::encode(hash(&salt, &secret)))
+        .bind(Utc::now())
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((row.into(), format!("{}.{}", id, secret)))
+    }
+
+    pub async fn list(&self) -> Result<Vec<ApiKey>, Error> {
+        let rows = sqlx::query_as::<_, ApiKeyRow>(&format!(
+            "select {} from api_keys order by created_at, id",
+            API_KEY_COLUMNS
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(ApiKey::from).collect())
+    }
+
+    // Returns false when there is no active key with the given id
+    pub async fn revoke(&self, id: &str) -> Result<bool, Error> {
+        let result =
+            sqlx::query("update api_keys set revoked_at = ? where id = ? and revoked_at is null")
+                .bind(Utc::now())
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Unknown, revoked and mismatching keys are rejected alike
+    pub async fn authenticate(&self, key: &str) -> Result<Principal, Error> {
+        let invalid = || Error::Unauthorized("Invalid API key".to_owned());
+        let (id, secret) = key.trim().split_once('.').ok_or_else(invalid)?;
+
+        let row = sqlx::query_as::<_, ApiKeyRow>(&format!(
+            "select {} from api_keys where id = ? and revoked_at is null",
+            API_KEY_COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(invalid)?;
+
+        let salt = hex::decode(&row.salt).map_err(|_| invalid())?;
+        let expected = hex::decode(&row.hash).map_err(|_| invalid())?;
+        if !constant_time_eq(&hash(&salt, secret), &expected) {
+            return Err(invalid());
+        }
+        if row
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            return Err(Error::Unauthorized("API key has expired".to_owned()));
+        }
+
+        let key = ApiKey::from(row);
+        Ok(Principal {
+            subject: key.subject,
+            tenant_id: key.tenant_id,
+            roles: key.scopes,
+        })
+    }
+}
+
+fn hash(salt: &[u8], secret: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(secret.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Compares every byte so the timing does not reveal how much of a guessed secret was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn api_key_store_should_authenticate_active_keys() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = ApiKeyStore::new(pool);
+
+        let (created, key) = store
+            .create(
+                "nightly export",
+                "export-job",
+                Some("acme"),
+                &[Role::Reader],
+                None,
+            )
+            .await
+            .unwrap();
+        let principal = store.authenticate(&key).await.unwrap();
+        assert_eq!(principal.subject, "export-job");
+        assert_eq!(principal.tenant_id, Some("acme".to_owned()));
+        assert_eq!(principal.roles, vec![Role::Reader]);
+
+        let forged = format!("{}.{}", created.id, "0".repeat(SECRET_BYTES * 2));
+        for key in [forged.as_str(), "unknown.secret", "malformed"] {
+            assert!(matches!(
+                store.authenticate(key).await,
+                Err(Error::Unauthorized(_))
+            ));
+        }
+
+        let (_, expired) = store
+            .create(
+                "old job",
+                "old-job",
+                None,
+                &[Role::Editor],
+                Some(Utc::now() - Duration::minutes(1)),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.authenticate(&expired).await,
+            Err(Error::Unauthorized(message)) if message == "API key has expired"
+        ));
+
+        assert!(store.revoke(&created.id).await.unwrap());
+        assert!(!store.revoke(&created.id).await.unwrap());
+        assert!(matches!(
+            store.authenticate(&key).await,
+            Err(Error::Unauthorized(_))
+        ));
+
+        let keys = store.list().await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys
+            .iter()
+            .find(|key| key.id == created.id)
+            .is_some_and(|key| key.revoked_at.is_some()));
+    }
+}
@@ -0,0 +1,350 @@
+use jsonwebtoken::{
+    decode, decode_header, errors::ErrorKind, jwk::JwkSet, Algorithm, DecodingKey, Validation,
+};
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    error::Error as StdError,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{info, warn};
+
+use super::Principal;
+use crate::config::AuthConfig;
+use crate::error::Error;
+
+// Tokens carrying an unknown key id trigger a JWKS refresh, at most once per interval
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    // Roles unknown to the service are ignored
+    #[serde(default)]
+    roles: Vec<String>,
+    // Looked up by the configured name, such as the tenant claim
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+// Signing keys published in a JWKS, keyed by their optional key id
+struct PublicKey {
+    kid: Option<String>,
+    key: DecodingKey,
+}
+
+pub struct JwtAuthenticator {
+    issuer: Option<String>,
+    audience: Option<String>,
+    tenant_claim: String,
+    leeway: Duration,
+    secret: Option<DecodingKey>,
+    public_keys: RwLock<Vec<PublicKey>>,
+    jwks_url: Option<String>,
+    last_refresh: Mutex<Instant>,
+    http: reqwest::Client,
+}
+
+impl JwtAuthenticator {
+    // Loads the configured keys, an unreadable JWKS prevents the service from starting
+    pub async fn new(config: &AuthConfig) -> Result<Self, Box<dyn StdError>> {
+        let http = reqwest::Client::builder()
+            .timeout(JWKS_FETCH_TIMEOUT)
+            .build()?;
+
+        let jwks = match (&config.jwks_file, &config.jwks_url) {
+            (Some(path), _) => Some(
+                serde_json::from_slice::<JwkSet>(&std::fs::read(path)?)
+                    .map_err(|e| format!("Invalid JWKS file '{}': {}", path.display(), e))?,
+            ),
+            (None, Some(url)) => Some(fetch_jwks(&http, url).await?),
+            (None, None) => None,
+        };
+        let public_keys = match jwks {
+            Some(jwks) => public_keys(&jwks)?,
+            None => vec![],
+        };
+        info!("Loaded {} JWKS signing keys", public_keys.len());
+
+        Ok(Self {
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            tenant_claim: config.tenant_claim.clone(),
+            leeway: config.leeway,
+            secret: config
+                .hs256_secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            public_keys: RwLock::new(public_keys),
+            jwks_url: config.jwks_url.clone(),
+            last_refresh: Mutex::new(Instant::now()),
+            http,
+        })
+    }
+
+    pub async fn authenticate(&self, token: &str) -> Result<Principal, Error> {
+        let header = decode_header(token)
+            .map_err(|_| Error::Unauthorized("Malformed bearer token".to_owned()))?;
+
+        let key = match header.alg {
+            Algorithm::HS256 => self.secret.clone(),
+            Algorithm::RS256 | Algorithm::ES256 => self.public_key(header.kid.as_deref()).await,
+            _ => None,
+        }
+        .ok_or_else(|| Error::Unauthorized("No key to verify the bearer token".to_owned()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let mut claims = decode::<Claims>(token, &key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => {
+                    Error::Unauthorized("Bearer token has expired".to_owned())
+                }
+                ErrorKind::ImmatureSignature => {
+                    Error::Unauthorized("Bearer token is not valid yet".to_owned())
+                }
+                // The token is genuine but was meant for another service
+                ErrorKind::InvalidIssuer | ErrorKind::InvalidAudience => {
+                    Error::Forbidden("Bearer token was not issued for this service".to_owned())
+                }
+                _ => Error::Unauthorized("Invalid bearer token".to_owned()),
+            })?
+            .claims;
+
+        Ok(Principal {
+            subject: claims.sub,
+            tenant_id: match claims.other.remove(&self.tenant_claim) {
+                Some(Value::String(tenant_id)) if !tenant_id.is_empty() => Some(tenant_id),
+                _ => None,
+            },
+            roles: claims
+                .roles
+                .iter()
+                .filter_map(|role| role.parse().ok())
+                .collect(),
+        })
+    }
+
+    async fn public_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        if let Some(key) = find_key(&self.public_keys.read().await, kid) {
+            return Some(key);
+        }
+
+        // The issuer might have rotated its keys since they were last fetched
+        let url = self.jwks_url.as_ref()?;
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.elapsed() < JWKS_REFRESH_INTERVAL {
+            return None;
+        }
+        *last_refresh = Instant::now();
+
+        // Errors are stringified right away, boxed errors can not be held across an await
+        let refreshed = fetch_jwks(&self.http, url)
+            .await
+            .and_then(|jwks| public_keys(&jwks))
+            .map_err(|e| e.to_string());
+        match refreshed {
+            Ok(keys) => {
+                info!("Refreshed JWKS, {} signing keys", keys.len());
+                *self.public_keys.write().await = keys;
+            }
+            Err(e) => warn!("Failed to refresh JWKS from {}: {}", url, e),
+        }
+
+        find_key(&self.public_keys.read().await, kid)
+    }
+}
+
+// Without a key id in the token only a single published key is unambiguous
+fn find_key(keys: &[PublicKey], kid: Option<&str>) -> Option<DecodingKey> {
+    match kid {
+        Some(kid) => keys.iter().find(|key| key.kid.as_deref() == Some(kid)),
+        None if keys.len() == 1 => keys.first(),
+        None => None,
+    }
+    .map(|key| key.key.clone())
+}
+
+fn public_keys(jwks: &JwkSet) -> Result<Vec<PublicKey>, Box<dyn StdError>> {
+    jwks.keys
+        .iter()
+        .map(|jwk| {
+            Ok(PublicKey {
+                kid: jwk.common.key_id.clone(),
+                key: DecodingKey::from_jwk(jwk)?,
+            })
+        })
+        .collect()
+}
+
+async fn fetch_jwks(http: &reqwest::Client, url: &str) -> Result<JwkSet, Box<dyn StdError>> {
+    let jwks = http
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to fetch JWKS from {}: {}", url, e))?
+        .json()
+        .await
+        .map_err(|e| format!("Invalid JWKS from {}: {}", url, e))?;
+
+    Ok(jwks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Role;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            enabled: true,
+            api_keys: false,
+            issuer: Some("https://issuer.example".to_owned()),
+            audience: Some("todos".to_owned()),
+            tenant_claim: "tenant_id".to_owned(),
+            hs256_secret: Some("secret".to_owned()),
+            jwks_file: Some(PathBuf::from("src/auth/testdata/jwks.json")),
+            jwks_url: None,
+            leeway: Duration::from_secs(0),
+            trust_role_headers: false,
+        }
+    }
+
+    fn claims(overrides: Value) -> Value {
+        let mut claims = json!({
+            "sub": "alice",
+            "iss": "https://issuer.example",
+            "aud": "todos",
+            "exp": get_current_timestamp() + 60,
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(overrides.as_object().unwrap().clone());
+        claims
+    }
+
+    fn hs256(claims: &Value) -> String {
+        encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn authenticate_should_accept_valid_tokens() {
+        let authenticator = JwtAuthenticator::new(&config()).await.unwrap();
+
+        let principal = authenticator
+            .authenticate(&hs256(&claims(
+                json!({"roles": ["admin", "auditor"], "tenant_id": "acme"}),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(principal.subject, "alice");
+        assert_eq!(principal.tenant_id, Some("acme".to_owned()));
+        assert_eq!(principal.roles, vec![Role::Admin]);
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("test-key".to_owned());
+        let token = encode(
+            &header,
+            &claims(json!({"sub": "bob"})),
+            &EncodingKey::from_ec_pem(include_bytes!("testdata/es256.pem")).unwrap(),
+        )
+        .unwrap();
+        let principal = authenticator.authenticate(&token).await.unwrap();
+        assert_eq!(principal.subject, "bob");
+        assert_eq!(principal.tenant_id, None);
+    }
+
+    #[tokio::test]
+    async fn new_should_fetch_jwks_from_url() {
+        let app = axum::Router::new().route(
+            "/jwks.json",
+            axum::routing::get(|| async { include_str!("testdata/jwks.json") }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/jwks.json", server.local_addr());
+        tokio::spawn(server);
+
+        let config = AuthConfig {
+            hs256_secret: None,
+            jwks_file: None,
+            jwks_url: Some(url),
+            ..config()
+        };
+        let authenticator = JwtAuthenticator::new(&config).await.unwrap();
+
+        // Single published key, tokens without a key id are accepted as well
+        let token = encode(
+            &Header::new(Algorithm::ES256),
+            &claims(json!({})),
+            &EncodingKey::from_ec_pem(include_bytes!("testdata/es256.pem")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            authenticator.authenticate(&token).await.unwrap().subject,
+            "alice"
+        );
+        assert!(matches!(
+            authenticator.authenticate(&hs256(&claims(json!({})))).await,
+            Err(Error::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn authenticate_should_reject_invalid_tokens() {
+        let authenticator = JwtAuthenticator::new(&config()).await.unwrap();
+        let now = get_current_timestamp();
+
+        for claims in [
+            claims(json!({"exp": now - 10})),
+            claims(json!({"nbf": now + 60})),
+        ] {
+            assert!(matches!(
+                authenticator.authenticate(&hs256(&claims)).await,
+                Err(Error::Unauthorized(_))
+            ));
+        }
+        for claims in [
+            claims(json!({"aud": "billing"})),
+            claims(json!({"iss": "https://other.example"})),
+        ] {
+            assert!(matches!(
+                authenticator.authenticate(&hs256(&claims)).await,
+                Err(Error::Forbidden(_))
+            ));
+        }
+
+        let forged = encode(
+            &Header::default(),
+            &claims(json!({})),
+            &EncodingKey::from_secret(b"guessed"),
+        )
+        .unwrap();
+        assert!(matches!(
+            authenticator.authenticate(&forged).await,
+            Err(Error::Unauthorized(_))
+        ));
+    }
+}
@@ -0,0 +1,286 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        request::Parts,
+        HeaderMap, HeaderValue, Request,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::model::Role;
+
+mod api_key;
+mod jwt;
+
+pub use api_key::ApiKeyStore;
+pub use jwt::JwtAuthenticator;
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+// Authenticated caller, inserted into the request extensions by the authentication layer.
+// The tenant comes from the token or the key as well, never from the request headers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub subject: String,
+    pub tenant_id: Option<String>,
+    pub roles: Vec<Role>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or_else(|| Error::Unauthorized("Request is not authenticated".to_owned()))
+    }
+}
+
+// Accepts JWT bearer tokens and `X-Api-Key` keys, whichever schemes are configured
+pub struct Authenticator {
+    jwt: Option<JwtAuthenticator>,
+    api_keys: Option<ApiKeyStore>,
+}
+
+impl Authenticator {
+    pub fn new(jwt: Option<JwtAuthenticator>, api_keys: Option<ApiKeyStore>) -> Self {
+        Self { jwt, api_keys }
+    }
+
+    // An API key takes precedence, callers are not expected to send both
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, Error> {
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            let key = key
+                .to_str()
+                .map_err(|_| Error::Unauthorized("Invalid API key".to_owned()))?;
+            return match &self.api_keys {
+                Some(api_keys) => api_keys.authenticate(key).await,
+                None => Err(Error::Unauthorized("API keys are not accepted".to_owned())),
+            };
+        }
+
+        match (bearer_token(headers), &self.jwt) {
+            (Some(token), Some(jwt)) => jwt.authenticate(token).await,
+            (Some(_), None) => Err(Error::Unauthorized(
+                "Bearer tokens are not accepted".to_owned(),
+            )),
+            (None, _) => Err(Error::Unauthorized(
+                "Missing bearer token or API key".to_owned(),
+            )),
+        }
+    }
+}
+
+// Middleware rejecting unauthenticated requests, use with `from_fn_with_state`
+pub async fn authenticate<B>(
+    State(authenticator): State<Arc<Authenticator>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match authenticator.authenticate(request.headers()).await {
+        Ok(principal) => {
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Err(error) => {
+            let unauthorized = matches!(error, Error::Unauthorized(_));
+            let mut response = error.into_response();
+            if unauthorized {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            response
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    match scheme.eq_ignore_ascii_case("bearer") {
+        true => Some(token.trim()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use hyper::StatusCode;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    async fn whoami(principal: Principal) -> String {
+        principal.subject
+    }
+
+    #[tokio::test]
+    async fn authenticate_should_require_a_valid_bearer_token_or_api_key() {
+        let config = AuthConfig {
+            enabled: true,
+            api_keys: false,
+            issuer: None,
+            audience: None,
+            tenant_claim: "tenant_id".to_owned(),
+            hs256_secret: Some("secret".to_owned()),
+            jwks_file: None,
+            jwks_url: None,
+            leeway: Duration::from_secs(0),
+            trust_role_headers: false,
+        };
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        crate::database::SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let api_keys = ApiKeyStore::new(pool);
+        let (_, api_key) = api_keys
+            .create("job", "export-job", None, &[Role::Reader], None)
+            .await
+            .unwrap();
+        let authenticator = Arc::new(Authenticator::new(
+            Some(JwtAuthenticator::new(&config).await.unwrap()),
+            Some(api_keys),
+        ));
+        let app = Router::new()
+            .route("/whoami", get(whoami))
+            .route_layer(middleware::from_fn_with_state(authenticator, authenticate));
+
+        let request = |header: Option<(&str, String)>| {
+            let mut builder = Request::builder().uri("/whoami");
+            if let Some((name, value)) = header {
+                builder = builder.header(name, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+
+        let token = encode(
+            &Header::default(),
+            &json!({"sub": "alice", "exp": jsonwebtoken::get_current_timestamp() + 60}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let response = app
+            .clone()
+            .oneshot(request(Some((
+                "authorization",
+                format!("Bearer {}", token),
+            ))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"alice");
+
+        // API keys produce the same principal
+        let response = app
+            .clone()
+            .oneshot(request(Some(("x-api-key", api_key))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"export-job");
+
+        let response = app
+            .oneshot(request(Some(("x-api-key", "unknown.secret".to_owned()))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn authenticated_callers_should_be_scoped_to_the_tenant_of_their_token() {
+        use crate::{
+            handlers::list_todos_handler,
+            model::{Identity, TodoInput, TodoState},
+            todo_store::inmemory::InMemoryTodoStore,
+            use_cases::{TodoInputPort, TodoInputPortArc, TodoService},
+        };
+        use axum::Extension;
+
+        let config = AuthConfig {
+            enabled: true,
+            api_keys: false,
+            issuer: None,
+            audience: None,
+            tenant_claim: "org".to_owned(),
+            hs256_secret: Some("secret".to_owned()),
+            jwks_file: None,
+            jwks_url: None,
+            leeway: Duration::from_secs(0),
+            trust_role_headers: false,
+        };
+        let todo_service = TodoService::new(Arc::new(InMemoryTodoStore::new()));
+        let todo = |text: &str| TodoInput {
+            text: text.to_owned(),
+            state: TodoState::Opened,
+        };
+        todo_service
+            .create_todo(&Identity::new("bob", Some("globex")), todo("Globex plans"))
+            .await
+            .unwrap();
+        todo_service
+            .create_todo(&Identity::new("alice", Some("acme")), todo("Acme plans"))
+            .await
+            .unwrap();
+
+        let authenticator = Arc::new(Authenticator::new(
+            Some(JwtAuthenticator::new(&config).await.unwrap()),
+            None,
+        ));
+        let app = Router::new()
+            .route("/api/v1/todos", get(list_todos_handler))
+            .route_layer(middleware::from_fn_with_state(authenticator, authenticate))
+            .layer(Extension(Arc::new(todo_service) as TodoInputPortArc));
+
+        let token = encode(
+            &Header::default(),
+            &json!({
+                "sub": "alice",
+                "org": "acme",
+                "roles": ["admin"],
+                "exp": jsonwebtoken::get_current_timestamp() + 60,
+            }),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        // Headers naming another tenant, user and roles are ignored for authenticated callers
+        let request = Request::builder()
+            .uri("/api/v1/todos")
+            .header("authorization", format!("Bearer {}", token))
+            .header("x-tenant-id", "globex")
+            .header("x-user-id", "bob")
+            .header("x-user-roles", "admin")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page["total_count"], 1);
+        assert_eq!(page["items"][0]["text"], "Acme plans");
+    }
+}
@@ -0,0 +1,101 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::model::Role;
+
+// Command line flags are the last (highest priority) configuration layer.
+// Values are kept as raw strings so they are validated together with the other layers.
+#[derive(Parser, Debug, Default, Clone)]
+#[command(version, about = "Sample todo service built with Axum")]
+pub struct Cli {
+    /// Optional TOML or YAML configuration file
+    #[arg(short, long, env = "APP_CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Address the HTTP server binds to
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port the HTTP server listens on
+    #[arg(long)]
+    pub port: Option<String>,
+
+    /// Port the Prometheus metrics are served on
+    #[arg(long)]
+    pub metrics_port: Option<String>,
+
+    /// Database connection url
+    #[arg(long)]
+    pub database_url: Option<String>,
+
+    /// Todo store implementation (sqlite, postgres, memory)
+    #[arg(long)]
+    pub store_backend: Option<String>,
+
+    /// Log level (TRACE, DEBUG, INFO, WARN, ERROR) or filter directives (e.g. info,sqlx=warn)
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Log format (json, kvp)
+    #[arg(long)]
+    pub log_format: Option<String>,
+
+    /// Runs a maintenance command instead of the HTTP server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Manage the API keys of service-to-service callers
+    #[command(subcommand)]
+    ApiKey(ApiKeyCommand),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ApiKeyCommand {
+    /// Create a key, the key is only printed once
+    Create {
+        /// Description of the caller the key is handed to
+        #[arg(long)]
+        name: String,
+
+        /// Subject the requests are made on behalf of
+        #[arg(long)]
+        subject: String,
+
+        /// Tenant the requests are scoped to, none by default
+        #[arg(long)]
+        tenant: Option<String>,
+
+        /// Roles granted to the key (reader, editor, admin)
+        #[arg(long, value_delimiter = ',', default_value = "editor")]
+        scopes: Vec<Role>,
+
+        /// Lifetime of the key (e.g. 90days), keys do not expire by default
+        #[arg(long)]
+        expires_in: Option<humantime::Duration>,
+    },
+    /// List all keys, including revoked and expired ones
+    List,
+    /// Revoke a key by its id
+    Revoke { id: String },
+}
+
+impl Cli {
+    // Flags mapped onto their configuration keys, only those actually passed are returned
+    pub fn overrides(&self) -> Vec<(&'static str, &'static str, String)> {
+        [
+            ("server.host", "--host", &self.host),
+            ("server.port", "--port", &self.port),
+            ("metrics.port", "--metrics-port", &self.metrics_port),
+            ("database.url", "--database-url", &self.database_url),
+            ("store.backend", "--store-backend", &self.store_backend),
+            ("logging.level", "--log-level", &self.log_level),
+            ("logging.format", "--log-format", &self.log_format),
+        ]
+        .into_iter()
+        .filter_map(|(key, flag, value)| value.clone().map(|v| (key, flag, v)))
+        .collect()
+    }
+}
@@ -0,0 +1,335 @@
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgPool, PgPoolOptions},
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions},
+};
+use std::{error::Error, fs, path::Path, str::FromStr};
+use tracing::{info, warn};
+
+use crate::config::{DatabaseBackend, DatabaseConfig};
+
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+pub static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+// Connection pool of the configured database backend
+#[derive(Clone, Debug)]
+pub enum DatabasePool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+impl DatabasePool {
+    // Connects to the database and brings its schema and data up to date
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self, Box<dyn Error>> {
+        let pool = Self::migrate(config).await?;
+        pool.adopt_ownerless_todos(
+            config.legacy_owner_id.as_deref(),
+            config.legacy_tenant_id.as_deref(),
+        )
+        .await?;
+
+        Ok(pool)
+    }
+
+    // Connects to the database and brings its schema up to date, leaving the todos untouched.
+    // Maintenance commands use it, they have no business with ownerless todos.
+    pub async fn migrate(config: &DatabaseConfig) -> Result<Self, Box<dyn Error>> {
+        match config.backend {
+            DatabaseBackend::Sqlite => {
+                let pool = connect_sqlite(config).await?;
+                SQLITE_MIGRATOR.run(&pool).await?;
+                Ok(DatabasePool::Sqlite(pool))
+            }
+            DatabaseBackend::Postgres => {
+                let pool = connect_postgres(config).await?;
+                POSTGRES_MIGRATOR.run(&pool).await?;
+                Ok(DatabasePool::Postgres(pool))
+            }
+        }
+    }
+
+    // Todos created before ownership was introduced have neither an owner nor a tenant, which
+    // hides them from everyone. They are handed to the configured legacy owner and tenant, without
+    // either the service refuses to start rather than silently losing them.
+    async fn adopt_ownerless_todos(
+        &self,
+        owner_id: Option<&str>,
+        tenant_id: Option<&str>,
+    ) -> Result<u64, Box<dyn Error>> {
+        let count: i64 = match self {
+            DatabasePool::Sqlite(pool) => {
+                sqlx::query_scalar(
+                    "select count(*) from todos where owner_id = '' and tenant_id = ''",
+                )
+                .fetch_one(pool)
+                .await?
+            }
+            DatabasePool::Postgres(pool) => {
+                sqlx::query_scalar(
+                    "select count(*) from todos where owner_id = '' and tenant_id = ''",
+                )
+                .fetch_one(pool)
+                .await?
+            }
+        };
+        if count == 0 {
+            return Ok(0);
+        }
+        if owner_id.is_none() && tenant_id.is_none() {
+            return Err(format!(
+                "{} todos created before ownership was introduced have no owner, set \
+                 database.legacy_owner_id and/or database.legacy_tenant_id or assign them with \
+                 UPDATE todos SET owner_id = '<user id>', tenant_id = '<tenant id>' \
+                 WHERE owner_id = '' AND tenant_id = ''",
+                count
+            )
+            .into());
+        }
+
+        let (owner_id, tenant_id) = (owner_id.unwrap_or_default(), tenant_id.unwrap_or_default());
+        let adopted = match self {
+            DatabasePool::Sqlite(pool) => sqlx::query(
+                "update todos set owner_id = ?, tenant_id = ? \
+                 where owner_id = '' and tenant_id = ''",
+            )
+            .bind(owner_id)
+            .bind(tenant_id)
+            .execute(pool)
+            .await?
+            .rows_affected(),
+            DatabasePool::Postgres(pool) => sqlx::query(
+                "update todos set owner_id = $1, tenant_id = $2 \
+                 where owner_id = '' and tenant_id = ''",
+            )
+            .bind(owner_id)
+            .bind(tenant_id)
+            .execute(pool)
+            .await?
+            .rows_affected(),
+        };
+        info!(
+            "Assigned {} ownerless todos to owner '{}' of tenant '{}'",
+            adopted, owner_id, tenant_id
+        );
+
+        Ok(adopted)
+    }
+
+    pub fn backend(&self) -> &'static str {
+        match self {
+            DatabasePool::Sqlite(_) => "sqlite",
+            DatabasePool::Postgres(_) => "postgres",
+        }
+    }
+
+    pub fn migrator(&self) -> &'static Migrator {
+        match self {
+            DatabasePool::Sqlite(_) => &SQLITE_MIGRATOR,
+            DatabasePool::Postgres(_) => &POSTGRES_MIGRATOR,
+        }
+    }
+
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        match self {
+            DatabasePool::Sqlite(pool) => sqlx::query("select 1").execute(pool).await.map(|_| ()),
+            DatabasePool::Postgres(pool) => sqlx::query("select 1").execute(pool).await.map(|_| ()),
+        }
+    }
+
+    pub async fn applied_migrations(&self) -> Result<Vec<i64>, sqlx::Error> {
+        let query = "select version from _sqlx_migrations where success = true";
+
+        match self {
+            DatabasePool::Sqlite(pool) => sqlx::query_scalar(query).fetch_all(pool).await,
+            DatabasePool::Postgres(pool) => sqlx::query_scalar(query).fetch_all(pool).await,
+        }
+    }
+
+    // Open connections and how many of them are idle
+    pub fn connection_stats(&self) -> (u32, usize) {
+        match self {
+            DatabasePool::Sqlite(pool) => (pool.size(), pool.num_idle()),
+            DatabasePool::Postgres(pool) => (pool.size(), pool.num_idle()),
+        }
+    }
+
+    pub async fn close(&self) {
+        match self {
+            DatabasePool::Sqlite(pool) => pool.close().await,
+            DatabasePool::Postgres(pool) => pool.close().await,
+        }
+    }
+}
+
+// Fail fast with a readable message rather than an opaque sqlx error on the first write
+fn ensure_writable_dir(db_file: &Path) -> Result<(), Box<dyn Error>> {
+    let dir = match db_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::File::create(&probe))
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| {
+            format!(
+                "Database directory '{}' is not writable: {}",
+                dir.display(),
+                e
+            )
+        })?;
+
+    Ok(())
+}
+
+async fn connect_sqlite(config: &DatabaseConfig) -> Result<SqlitePool, Box<dyn Error>> {
+    let mut connect_options = SqliteConnectOptions::from_str(&config.url)?
+        .busy_timeout(config.busy_timeout)
+        .synchronous(config.synchronous);
+
+    let mut pool_options = SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(config.acquire_timeout);
+
+    match config.file_path() {
+        Some(path) => {
+            ensure_writable_dir(&path)?;
+            connect_options = connect_options
+                .create_if_missing(true)
+                .journal_mode(config.journal_mode);
+        }
+        None => {
+            // In-memory database only lives as long as its connections
+            // Keep exactly one connection open for the lifetime of the pool
+            warn!("Using in-memory database, data will be lost on shutdown");
+            pool_options = pool_options
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+    }
+
+    Ok(pool_options.connect_with(connect_options).await?)
+}
+
+async fn connect_postgres(config: &DatabaseConfig) -> Result<PgPool, Box<dyn Error>> {
+    let pool = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(config.acquire_timeout)
+        .connect(&config.url)
+        .await?;
+
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+    use std::{env, time::Duration};
+
+    #[tokio::test]
+    async fn ownerless_todos_should_be_adopted_by_the_legacy_owner() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        sqlx::query("insert into todos (text, state) values ('Legacy', 'Opened')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let pool = DatabasePool::Sqlite(pool);
+
+        assert!(pool.adopt_ownerless_todos(None, None).await.is_err());
+        assert_eq!(
+            pool.adopt_ownerless_todos(Some("alice"), Some("acme"))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(pool.adopt_ownerless_todos(None, None).await.unwrap(), 0);
+
+        let DatabasePool::Sqlite(pool) = pool else {
+            unreachable!()
+        };
+        let owner: (String, String) = sqlx::query_as("select owner_id, tenant_id from todos")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(owner, ("alice".to_owned(), "acme".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn audit_fields_migration_should_backfill_closed_at() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let migrator = |before: Option<i64>| Migrator {
+            migrations: SQLITE_MIGRATOR
+                .migrations
+                .iter()
+                .filter(|migration| before.is_none_or(|version| migration.version < version))
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        migrator(Some(3)).run(&pool).await.unwrap();
+        sqlx::query(
+            "insert into todos (text, state) values ('Open', 'Opened'), ('Done', 'Closed')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        migrator(None).run(&pool).await.unwrap();
+
+        let closed_at: Vec<(String, Option<String>, String)> =
+            sqlx::query_as("select state, closed_at, updated_at from todos order by id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(closed_at[0].1, None);
+        assert_eq!(closed_at[1].1.as_ref(), Some(&closed_at[1].2));
+    }
+
+    #[tokio::test]
+    async fn migrate_should_leave_ownerless_todos_alone() {
+        let path = env::temp_dir().join(format!("migrate-test-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = DatabaseConfig {
+            url: format!("sqlite://{}", path.display()),
+            backend: DatabaseBackend::Sqlite,
+            max_connections: 1,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(5),
+            busy_timeout: Duration::from_secs(5),
+            journal_mode: SqliteJournalMode::Delete,
+            synchronous: SqliteSynchronous::Full,
+            legacy_owner_id: None,
+            legacy_tenant_id: None,
+        };
+
+        let DatabasePool::Sqlite(pool) = DatabasePool::migrate(&config).await.unwrap() else {
+            unreachable!()
+        };
+        sqlx::query("insert into todos (text, state) values ('Legacy', 'Opened')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        // Migrating again succeeds where connecting refuses the ownerless todo
+        assert!(DatabasePool::connect(&config).await.is_err());
+        let DatabasePool::Sqlite(pool) = DatabasePool::migrate(&config).await.unwrap() else {
+            unreachable!()
+        };
+        let owner: (String, String) = sqlx::query_as("select owner_id, tenant_id from todos")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(owner, (String::new(), String::new()));
+
+        pool.close().await;
+        fs::remove_file(&path).unwrap();
+    }
+}
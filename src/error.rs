@@ -1,5 +1,5 @@
 use axum::{
-    extract::rejection::{JsonRejection, PathRejection},
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
     http::StatusCode,
     response::IntoResponse,
 };
@@ -9,6 +9,8 @@ use thiserror::Error;
 use tracing::error;
 use validator::ValidationErrors;
 
+use crate::request_id::RequestId;
+
 pub type Result<T> = std::result::Result<T, Error>;
 pub type HttpResult<T> = std::result::Result<T, ApiError>;
 
@@ -17,15 +19,36 @@ pub enum Error {
     #[error("Requested resource '{name}' with ID: {id} not found")]
     ResourceNotFound { name: String, id: u32 },
 
+    #[error("Unauthorized")]
+    Unauthorized(String),
+
+    #[error("Forbidden")]
+    Forbidden(String),
+
+    #[error("Precondition failed")]
+    PreconditionFailed(String),
+
     #[error("Error extracting json payload")]
     JSONExtractor(#[from] JsonRejection),
 
     #[error("Error extracting path parameters")]
     PathExtractor(#[from] PathRejection),
 
+    #[error("Error extracting query parameters")]
+    QueryExtractor(#[from] QueryRejection),
+
+    #[error("Invalid query parameters")]
+    InvalidQuery(String),
+
     #[error("Invalid request body")]
     Validator(#[from] ValidationErrors),
 
+    #[error("Invalid request body")]
+    InvalidPayload(String),
+
+    #[error("Operation not applied")]
+    Aborted(String),
+
     #[error("Unexpected error")]
     Unexpected(anyhow::Error),
 }
@@ -34,9 +57,16 @@ impl Error {
     pub fn error_code(&self) -> &str {
         match &self {
             Error::ResourceNotFound { name: _, id: _ } => "error.entity.not-found",
+            Error::Unauthorized(_) => "error.auth.unauthorized",
+            Error::Forbidden(_) => "error.auth.forbidden",
+            Error::PreconditionFailed(_) => "error.entity.precondition-failed",
             Error::JSONExtractor(_) => "error.payload.invalid",
             Error::Validator(_) => "error.payload.invalid",
+            Error::InvalidPayload(_) => "error.payload.invalid",
             Error::PathExtractor(_) => "error.path-parms.invalid",
+            Error::QueryExtractor(_) => "error.query-params.invalid",
+            Error::InvalidQuery(_) => "error.query-params.invalid",
+            Error::Aborted(_) => "error.batch.aborted",
             _ => "error.unexpected",
         }
     }
@@ -44,18 +74,32 @@ impl Error {
     pub fn status_code(&self) -> StatusCode {
         match &self {
             Error::ResourceNotFound { name: _, id: _ } => StatusCode::NOT_FOUND,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
             Error::JSONExtractor(_) => StatusCode::BAD_REQUEST,
             Error::PathExtractor(_) => StatusCode::BAD_REQUEST,
+            Error::QueryExtractor(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidQuery(_) => StatusCode::BAD_REQUEST,
             Error::Validator(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            Error::Aborted(_) => StatusCode::FAILED_DEPENDENCY,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     pub fn details(&self) -> Option<String> {
         match &self {
+            Error::Unauthorized(message) => Some(message.to_owned()),
+            Error::Forbidden(message) => Some(message.to_owned()),
+            Error::PreconditionFailed(message) => Some(message.to_owned()),
             Error::JSONExtractor(error) => Some(error.body_text()),
             Error::PathExtractor(error) => Some(error.body_text()),
+            Error::QueryExtractor(error) => Some(error.body_text()),
+            Error::InvalidQuery(message) => Some(message.to_owned()),
             Error::Validator(error) => Some(error.to_string()),
+            Error::InvalidPayload(message) => Some(message.to_owned()),
+            Error::Aborted(message) => Some(message.to_owned()),
             _ => None,
         }
     }
@@ -64,6 +108,18 @@ impl Error {
         format!("type://{}", &self.error_code())
     }
 
+    pub fn stale_version(entity_name: &str, entity_id: u32) -> Error {
+        Error::PreconditionFailed(format!(
+            "Resource '{}' with ID: {} has been modified",
+            entity_name, entity_id
+        ))
+    }
+
+    // Operation of an all-or-nothing batch which was rolled back or never run
+    pub fn batch_aborted() -> Error {
+        Error::Aborted("Another operation of the batch failed, nothing was applied".to_owned())
+    }
+
     pub fn from_with_context(error: sqlx::Error, entity_name: String, entity_id: u32) -> Error {
         match error {
             sqlx::Error::RowNotFound => Error::ResourceNotFound {
@@ -87,6 +143,11 @@ impl From<Error> for ApiError {
             api_error = api_error.message(detail);
         }
 
+        // Lets clients quote the id which correlates the error with the service logs
+        if let Some(request_id) = RequestId::current() {
+            api_error = api_error.field("request_id", request_id.as_str());
+        }
+
         // Should probably move this into a global error handler
         // And convert the error::Error to ApiError after handlers are done
         if let Error::Unexpected(err) = value {
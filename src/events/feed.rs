@@ -0,0 +1,274 @@
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::TodoEvent;
+use crate::model::{Identity, Scope, Todo, TodoState};
+
+// Change as delivered to live subscribers. Ids only grow within the process, a subscriber
+// resumes after a reconnect from the id of the last change it has seen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TodoChange {
+    pub id: u64,
+    pub tenant_id: Option<String>,
+    pub owner_id: String,
+    // State after the change, the last known state of a deleted todo
+    pub state: TodoState,
+    pub event: TodoEvent,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FeedItem {
+    Change(Box<TodoChange>),
+    // Changes were lost, either evicted from the replay buffer or skipped by a slow subscriber.
+    // The subscriber has to reload the todos it shows.
+    Missed,
+}
+
+struct Replay {
+    last_id: u64,
+    changes: VecDeque<TodoChange>,
+}
+
+// In-process fan-out of todo changes. The latest changes are kept to replay them to
+// reconnecting subscribers, ids are assigned under the same lock the changes are sent under
+// so that a subscriber sees every change exactly once, either replayed or live.
+pub struct TodoFeed {
+    sender: broadcast::Sender<TodoChange>,
+    replay: Mutex<Replay>,
+    capacity: usize,
+}
+
+impl TodoFeed {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self {
+            sender,
+            replay: Mutex::new(Replay {
+                last_id: 0,
+                changes: VecDeque::with_capacity(capacity),
+            }),
+            capacity,
+        }
+    }
+
+    // The todo belongs to the tenant of the identity that changed it, and to its owner as
+    // stored, who is not necessarily the user who created or changed it
+    pub fn publish(&self, identity: &Identity, todo: &Todo, event: TodoEvent) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.last_id += 1;
+
+        let change = TodoChange {
+            id: replay.last_id,
+            tenant_id: identity.tenant_id.clone(),
+            owner_id: todo.owner_id.clone(),
+            state: todo.state.clone(),
+            event,
+        };
+        if replay.changes.len() == self.capacity {
+            replay.changes.pop_front();
+        }
+        replay.changes.push_back(change.clone());
+
+        // Nobody may be listening, which is fine
+        let _ = self.sender.send(change);
+    }
+
+    // Ids the feed never issued, e.g. ones from before a restart, are reported as missed changes
+    pub fn subscribe(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        state: Option<TodoState>,
+        last_event_id: Option<&str>,
+    ) -> TodoSubscription {
+        let replay = self.replay.lock().unwrap();
+        let receiver = self.sender.subscribe();
+
+        let (missed, backlog) = match last_event_id.map(|id| id.trim().parse::<u64>()) {
+            None => (false, VecDeque::new()),
+            Some(Ok(last_id)) if last_id <= replay.last_id => {
+                let oldest_id = replay
+                    .changes
+                    .front()
+                    .map_or(replay.last_id + 1, |change| change.id);
+                let backlog = replay
+                    .changes
+                    .iter()
+                    .filter(|change| change.id > last_id)
+                    .cloned()
+                    .collect();
+                (last_id + 1 < oldest_id, backlog)
+            }
+            Some(_) => (true, VecDeque::new()),
+        };
+
+        TodoSubscription {
+            identity: identity.clone(),
+            scope,
+            state,
+            missed,
+            backlog,
+            receiver,
+        }
+    }
+}
+
+// Changes of the todos within the subscriber's scope, optionally only those in a given state
+pub struct TodoSubscription {
+    identity: Identity,
+    scope: Scope,
+    state: Option<TodoState>,
+    missed: bool,
+    backlog: VecDeque<TodoChange>,
+    receiver: broadcast::Receiver<TodoChange>,
+}
+
+impl TodoSubscription {
+    // Waits for the next change, None once the feed is gone
+    pub async fn next(&mut self) -> Option<FeedItem> {
+        if std::mem::take(&mut self.missed) {
+            return Some(FeedItem::Missed);
+        }
+
+        loop {
+            let change = match self.backlog.pop_front() {
+                Some(change) => change,
+                None => match self.receiver.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(_)) => return Some(FeedItem::Missed),
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+
+            if self.matches(&change) {
+                return Some(FeedItem::Change(Box::new(change)));
+            }
+        }
+    }
+
+    fn matches(&self, change: &TodoChange) -> bool {
+        let visible = change.tenant_id == self.identity.tenant_id
+            && (self.scope == Scope::Tenant || change.owner_id == self.identity.user_id);
+
+        visible
+            && self
+                .state
+                .as_ref()
+                .is_none_or(|state| *state == change.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn todo(id: u32, owner: &str, state: TodoState) -> Todo {
+        let now = Utc::now();
+        Todo {
+            id,
+            text: "Item".to_owned(),
+            state,
+            version: 1,
+            created_at: now,
+            updated_at: now,
+            closed_at: None,
+            created_by: Some(owner.to_owned()),
+            updated_by: Some(owner.to_owned()),
+            owner_id: owner.to_owned(),
+        }
+    }
+
+    fn publish(feed: &TodoFeed, id: u32, owner: &str, state: TodoState) {
+        let todo = todo(id, owner, state);
+        feed.publish(
+            &Identity::new(owner, None),
+            &todo,
+            TodoEvent::Created(todo.clone()),
+        );
+    }
+
+    async fn change_ids(subscription: &mut TodoSubscription, count: usize) -> Vec<u64> {
+        let mut ids = vec![];
+        for _ in 0..count {
+            match subscription.next().await {
+                Some(FeedItem::Change(change)) => ids.push(change.id),
+                other => panic!("Expected a change, got {:?}", other),
+            }
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn subscription_should_resume_after_the_last_seen_change() {
+        let feed = TodoFeed::new(3);
+        let alice = Identity::new("alice", None);
+        for id in 1..=4 {
+            publish(&feed, id, "alice", TodoState::Opened);
+        }
+
+        let mut resumed = feed.subscribe(&alice, Scope::Own, None, Some("2"));
+        let mut live = feed.subscribe(&alice, Scope::Own, None, None);
+        publish(&feed, 5, "alice", TodoState::Opened);
+        assert_eq!(change_ids(&mut resumed, 3).await, vec![3, 4, 5]);
+        assert_eq!(change_ids(&mut live, 1).await, vec![5]);
+
+        // Change 2 is no longer buffered, neither is anything from before a restart
+        for last_event_id in ["1", "42", "not-an-id"] {
+            let mut subscription = feed.subscribe(&alice, Scope::Own, None, Some(last_event_id));
+            assert_eq!(subscription.next().await, Some(FeedItem::Missed));
+        }
+    }
+
+    #[tokio::test]
+    async fn subscription_should_only_see_matching_changes_in_scope() {
+        let feed = TodoFeed::new(10);
+        let mut own_open = feed.subscribe(
+            &Identity::new("alice", None),
+            Scope::Own,
+            Some(TodoState::Opened),
+            None,
+        );
+        let mut tenant = feed.subscribe(&Identity::new("dave", None), Scope::Tenant, None, None);
+
+        publish(&feed, 1, "bob", TodoState::Opened);
+        publish(&feed, 2, "alice", TodoState::Closed);
+        publish(&feed, 3, "alice", TodoState::Opened);
+        let other_tenant = todo(4, "alice", TodoState::Opened);
+        feed.publish(
+            &Identity::new("alice", Some("acme")),
+            &other_tenant,
+            TodoEvent::Deleted { id: 4 },
+        );
+        publish(&feed, 5, "alice", TodoState::Opened);
+
+        assert_eq!(change_ids(&mut own_open, 2).await, vec![3, 5]);
+        assert_eq!(change_ids(&mut tenant, 4).await, vec![1, 2, 3, 5]);
+    }
+
+    #[tokio::test]
+    async fn subscription_should_follow_the_owner_rather_than_the_creator() {
+        let feed = TodoFeed::new(10);
+        let mut owner = feed.subscribe(&Identity::new("alice", None), Scope::Own, None, None);
+        let mut creator = feed.subscribe(&Identity::new("bob", None), Scope::Own, None, None);
+
+        // Legacy todos adopted by alice have no creator, others may have been created by bob
+        let mut adopted = todo(1, "alice", TodoState::Opened);
+        adopted.created_by = None;
+        let mut handed_over = todo(2, "alice", TodoState::Opened);
+        handed_over.created_by = Some("bob".to_owned());
+        for todo in [adopted, handed_over] {
+            feed.publish(
+                &Identity::new("alice", None),
+                &todo,
+                TodoEvent::Updated(todo.clone()),
+            );
+        }
+        publish(&feed, 3, "bob", TodoState::Opened);
+
+        assert_eq!(change_ids(&mut owner, 2).await, vec![1, 2]);
+        assert_eq!(change_ids(&mut creator, 1).await, vec![3]);
+    }
+}
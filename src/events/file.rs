@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use std::{io, path::Path};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use super::{EventPublisher, OutboxEvent, PublishError};
+
+// Writes every event as a JSON line, to a file or to stdout.
+// Useful for local development and as a stand-in for a broker in tests.
+pub struct FilePublisher {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+}
+
+impl FilePublisher {
+    // Events are appended, the file is created when missing
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(Self {
+            writer: Mutex::new(Box::new(file)),
+        })
+    }
+
+    pub fn stdout() -> Self {
+        Self {
+            writer: Mutex::new(Box::new(tokio::io::stdout())),
+        }
+    }
+}
+
+#[async_trait]
+impl EventPublisher for FilePublisher {
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), PublishError> {
+        let mut line = serde_json::to_vec(event).map_err(|e| PublishError(e.to_string()))?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().await;
+        writer
+            .write_all(&line)
+            .await
+            .map_err(|e| PublishError(e.to_string()))?;
+        writer
+            .flush()
+            .await
+            .map_err(|e| PublishError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::TodoEvent;
+    use chrono::Utc;
+    use std::env;
+
+    #[tokio::test]
+    async fn file_publisher_should_append_json_lines() {
+        let path = env::temp_dir().join(format!("events-test-{}.jsonl", std::process::id()));
+        let publisher = FilePublisher::open(&path).await.unwrap();
+
+        for id in [1, 2] {
+            let deleted = TodoEvent::Deleted { id };
+            let event = OutboxEvent {
+                id: i64::from(id),
+                event_type: deleted.event_type().to_owned(),
+                todo_id: id,
+                tenant_id: String::new(),
+                occurred_at: Utc::now(),
+                data: deleted.data(),
+            };
+            publisher.publish(&event).await.unwrap();
+        }
+
+        let written = tokio::fs::read_to_string(&path).await;
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = written
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["type"], "TodoDeleted");
+        assert_eq!(lines[1]["data"]["id"], 2);
+    }
+}
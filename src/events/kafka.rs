@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use rdkafka::{
+    error::KafkaError,
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig,
+};
+use std::time::Duration;
+
+use super::{EventPublisher, OutboxEvent, PublishError};
+
+// How long a single event may wait in the producer queue before the publish fails
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Publishes events keyed by the todo id, so that the events of a todo stay ordered
+// within their partition. The event type is also sent as a header for routing.
+pub struct KafkaPublisher {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaPublisher {
+    pub fn new(brokers: &str, topic: &str) -> Result<Self, KafkaError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("enable.idempotence", "true")
+            .set("message.timeout.ms", "30000")
+            .create()?;
+
+        Ok(Self {
+            producer,
+            topic: topic.to_owned(),
+        })
+    }
+}
+
+#[async_trait]
+impl EventPublisher for KafkaPublisher {
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), PublishError> {
+        let (key, payload, headers) = encode(event)?;
+        let record = FutureRecord::to(&self.topic)
+            .key(&key)
+            .payload(&payload)
+            .headers(headers);
+
+        self.producer
+            .send(record, QUEUE_TIMEOUT)
+            .await
+            .map(|_| ())
+            .map_err(|(e, _)| PublishError(e.to_string()))
+    }
+}
+
+// Key, payload and headers of the record an event is published as
+fn encode(event: &OutboxEvent) -> Result<(String, String, OwnedHeaders), PublishError> {
+    let payload = serde_json::to_string(event).map_err(|e| PublishError(e.to_string()))?;
+    let headers = OwnedHeaders::new().insert(Header {
+        key: "event_type",
+        value: Some(event.event_type.as_str()),
+    });
+
+    Ok((event.todo_id.to_string(), payload, headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use rdkafka::message::Headers;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn encode_should_key_records_by_todo_id() {
+        let event = OutboxEvent {
+            id: 42,
+            event_type: "TodoClosed".to_owned(),
+            todo_id: 7,
+            tenant_id: "acme".to_owned(),
+            occurred_at: Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap(),
+            data: json!({ "id": 7, "text": "Write tests", "state": "Closed" }),
+        };
+
+        let (key, payload, headers) = encode(&event).unwrap();
+        assert_eq!(key, "7");
+        assert_eq!(
+            serde_json::from_str::<Value>(&payload).unwrap(),
+            json!({
+                "id": 42,
+                "type": "TodoClosed",
+                "todo_id": 7,
+                "tenant_id": "acme",
+                "occurred_at": "2024-05-01T12:00:00Z",
+                "data": { "id": 7, "text": "Write tests", "state": "Closed" },
+            })
+        );
+
+        let header = headers.get(0);
+        assert_eq!(header.key, "event_type");
+        assert_eq!(header.value, Some(&b"TodoClosed"[..]));
+    }
+}
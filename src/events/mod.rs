@@ -0,0 +1,183 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use std::{error::Error, sync::Arc};
+use tracing::info;
+
+use crate::config::{EventPublisherKind, EventsConfig};
+use crate::model::{Todo, TodoState, TodoWritten, UpdatedTodo};
+use file::FilePublisher;
+
+pub mod feed;
+pub mod file;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod outbox;
+
+// Types of the events, as published
+pub const EVENT_TYPES: &[&str] = &["TodoCreated", "TodoUpdated", "TodoClosed", "TodoDeleted"];
+
+// Changes of a todo, recorded by the store along with the change itself
+#[derive(Clone, Debug, PartialEq)]
+pub enum TodoEvent {
+    Created(Todo),
+    Updated(Todo),
+    Closed(Todo),
+    Deleted { id: u32 },
+}
+
+impl TodoEvent {
+    // Updates which close an opened todo are published as TodoClosed, edits of a closed todo
+    // and reopening it as TodoUpdated
+    pub fn changed(updated: UpdatedTodo) -> Self {
+        match (updated.previous_state, &updated.todo.state) {
+            (TodoState::Opened, TodoState::Closed) => TodoEvent::Closed(updated.todo),
+            _ => TodoEvent::Updated(updated.todo),
+        }
+    }
+
+    // Event of a write of a batch, none for deletes of missing todos
+    pub fn written(written: &TodoWritten) -> Option<Self> {
+        match written {
+            TodoWritten::Created(todo) => Some(TodoEvent::Created(todo.clone())),
+            TodoWritten::Updated(updated) => Some(TodoEvent::changed(updated.clone())),
+            TodoWritten::Deleted(todo) => {
+                todo.as_ref().map(|todo| TodoEvent::Deleted { id: todo.id })
+            }
+        }
+    }
+
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            TodoEvent::Created(_) => "TodoCreated",
+            TodoEvent::Updated(_) => "TodoUpdated",
+            TodoEvent::Closed(_) => "TodoClosed",
+            TodoEvent::Deleted { .. } => "TodoDeleted",
+        }
+    }
+
+    pub fn todo_id(&self) -> u32 {
+        match self {
+            TodoEvent::Created(todo) | TodoEvent::Updated(todo) | TodoEvent::Closed(todo) => {
+                todo.id
+            }
+            TodoEvent::Deleted { id } => *id,
+        }
+    }
+
+    pub fn data(&self) -> Value {
+        match self {
+            TodoEvent::Created(todo) | TodoEvent::Updated(todo) | TodoEvent::Closed(todo) => {
+                serde_json::to_value(todo).unwrap_or_default()
+            }
+            TodoEvent::Deleted { id } => json!({ "id": id }),
+        }
+    }
+}
+
+// Event as stored in the outbox and handed to the publishers. The id grows with every event,
+// consumers use it to drop the duplicates at-least-once delivery may produce.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct OutboxEvent {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub todo_id: u32,
+    pub tenant_id: String,
+    pub occurred_at: DateTime<Utc>,
+    pub data: Value,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to publish event: {0}")]
+pub struct PublishError(pub String);
+
+// Output port the outbox relay delivers the events through
+#[async_trait]
+pub trait EventPublisher {
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), PublishError>;
+}
+
+pub type EventPublisherArc = Arc<dyn EventPublisher + Send + Sync>;
+
+// Publishes every event to each of the publishers in turn. A failure fails the whole publish,
+// so publishers which already got the event get it again on the retry.
+pub struct FanOutPublisher(pub Vec<EventPublisherArc>);
+
+#[async_trait]
+impl EventPublisher for FanOutPublisher {
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), PublishError> {
+        for publisher in &self.0 {
+            publisher.publish(event).await?;
+        }
+        Ok(())
+    }
+}
+
+// Publisher factory, picks the implementation configured by `events.publisher`
+pub async fn init_event_publisher(
+    config: &EventsConfig,
+) -> Result<EventPublisherArc, Box<dyn Error>> {
+    let publisher: EventPublisherArc = match (config.publisher, &config.file_path) {
+        (EventPublisherKind::File, Some(path)) => {
+            info!("Publishing events to {}", path.display());
+            Arc::new(FilePublisher::open(path).await?)
+        }
+        #[cfg(feature = "kafka")]
+        (EventPublisherKind::Kafka, _) => {
+            let brokers = config.kafka_brokers.as_deref().unwrap_or_default();
+            info!("Publishing events to kafka topic {}", config.kafka_topic);
+            Arc::new(kafka::KafkaPublisher::new(brokers, &config.kafka_topic)?)
+        }
+        _ => {
+            info!("Publishing events to stdout");
+            Arc::new(FilePublisher::stdout())
+        }
+    };
+
+    Ok(publisher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn updated(previous_state: TodoState, state: TodoState) -> UpdatedTodo {
+        let now = Utc::now();
+        UpdatedTodo {
+            previous_state,
+            todo: Todo {
+                id: 7,
+                text: "Write tests".to_owned(),
+                closed_at: (state == TodoState::Closed).then_some(now - Duration::hours(1)),
+                state,
+                version: 2,
+                created_at: now,
+                updated_at: now,
+                created_by: None,
+                updated_by: None,
+                owner_id: "alice".to_owned(),
+            },
+        }
+    }
+
+    #[test]
+    fn changed_should_tell_closing_from_editing() {
+        use TodoState::{Closed, Opened};
+
+        for (previous_state, state, event_type) in [
+            (Opened, Closed, "TodoClosed"),
+            (Opened, Opened, "TodoUpdated"),
+            (Closed, Closed, "TodoUpdated"),
+            (Closed, Opened, "TodoUpdated"),
+        ] {
+            assert_eq!(
+                TodoEvent::changed(updated(previous_state, state)).event_type(),
+                event_type
+            );
+        }
+        assert_eq!(TodoEvent::Deleted { id: 7 }.data(), json!({ "id": 7 }));
+    }
+}
@@ -0,0 +1,232 @@
+use chrono::{DateTime, Utc};
+use sqlx::{
+    sqlite::{Sqlite, SqlitePool},
+    FromRow, Transaction,
+};
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+use super::{EventPublisherArc, OutboxEvent, TodoEvent};
+use crate::error::Error;
+
+#[derive(FromRow)]
+struct OutboxRow {
+    id: i64,
+    event_type: String,
+    todo_id: u32,
+    tenant_id: String,
+    payload: String,
+    occurred_at: DateTime<Utc>,
+}
+
+impl TryFrom<OutboxRow> for OutboxEvent {
+    type Error = Error;
+
+    fn try_from(row: OutboxRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.id,
+            event_type: row.event_type,
+            todo_id: row.todo_id,
+            tenant_id: row.tenant_id,
+            occurred_at: row.occurred_at,
+            data: serde_json::from_str(&row.payload).map_err(|e| Error::Unexpected(e.into()))?,
+        })
+    }
+}
+
+pub struct SqliteOutbox {
+    pool: SqlitePool,
+}
+
+impl SqliteOutbox {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    // Appends the event within the transaction of the change it describes,
+    // so that either both or none of them are stored
+    pub async fn record(
+        tx: &mut Transaction<'_, Sqlite>,
+        tenant_id: &str,
+        event: &TodoEvent,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "insert into outbox (event_type, todo_id, tenant_id, payload, occurred_at) \
+             values (?, ?, ?, ?, ?)",
+        )
+        .bind(event.event_type())
+        .bind(event.todo_id())
+        .bind(tenant_id)
+        .bind(event.data().to_string())
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn pending(&self, limit: u32) -> Result<Vec<OutboxEvent>, Error> {
+        let rows = sqlx::query_as::<_, OutboxRow>(
+            "select id, event_type, todo_id, tenant_id, payload, occurred_at from outbox \
+             where published_at is null order by id limit ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(OutboxEvent::try_from).collect()
+    }
+
+    async fn mark_published(&self, id: i64) -> Result<(), Error> {
+        sqlx::query("update outbox set published_at = ?, attempts = attempts + 1 where id = ?")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: i64, error: &str) -> Result<(), Error> {
+        sqlx::query("update outbox set attempts = attempts + 1, last_error = ? where id = ?")
+            .bind(error)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// Background task publishing the outbox in order. An event is only marked as published once
+// the publisher acknowledged it, a crash in between publishes it again (at-least-once).
+pub struct OutboxRelay {
+    outbox: SqliteOutbox,
+    publisher: EventPublisherArc,
+    batch_size: u32,
+    interval: Duration,
+}
+
+impl OutboxRelay {
+    pub fn new(
+        outbox: SqliteOutbox,
+        publisher: EventPublisherArc,
+        batch_size: u32,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            outbox,
+            publisher,
+            batch_size,
+            interval,
+        }
+    }
+
+    // Publishes a batch of pending events and returns how many were published.
+    // Stops at the first failure, later events are held back to keep the order.
+    pub async fn relay_pending(&self) -> Result<usize, Error> {
+        let events = self.outbox.pending(self.batch_size).await?;
+        let mut published = 0;
+
+        for event in &events {
+            match self.publisher.publish(event).await {
+                Ok(_) => {
+                    self.outbox.mark_published(event.id).await?;
+                    published += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to publish event {}, will retry: {}", event.id, e);
+                    self.outbox.mark_failed(event.id, &e.to_string()).await?;
+                    break;
+                }
+            }
+        }
+
+        Ok(published)
+    }
+
+    // Relays until the task is aborted, full batches are followed up immediately
+    pub async fn run(self) {
+        loop {
+            match self.relay_pending().await {
+                Ok(published) if published == self.batch_size as usize => continue,
+                Ok(published) if published > 0 => debug!("Published {} events", published),
+                Ok(_) => {}
+                Err(e) => error!("Failed to relay the outbox: {}", e),
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use crate::events::{EventPublisher, PublishError};
+    use async_trait::async_trait;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    // Broker stand-in which is unavailable for the first delivery
+    #[derive(Default)]
+    struct FlakyPublisher {
+        available: AtomicBool,
+        published: Mutex<Vec<OutboxEvent>>,
+    }
+
+    #[async_trait]
+    impl EventPublisher for FlakyPublisher {
+        async fn publish(&self, event: &OutboxEvent) -> Result<(), PublishError> {
+            if !self.available.swap(true, Ordering::SeqCst) {
+                return Err(PublishError("broker unavailable".to_owned()));
+            }
+            self.published.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_should_retry_until_every_event_is_published_in_order() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        for id in 1..=3 {
+            SqliteOutbox::record(&mut tx, "acme", &TodoEvent::Deleted { id })
+                .await
+                .unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let publisher = Arc::new(FlakyPublisher::default());
+        let relay = OutboxRelay::new(
+            SqliteOutbox::new(pool.clone()),
+            publisher.clone(),
+            10,
+            Duration::from_millis(10),
+        );
+
+        assert_eq!(relay.relay_pending().await.unwrap(), 0);
+        let (attempts, last_error): (i64, Option<String>) =
+            sqlx::query_as("select attempts, last_error from outbox where id = 1")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(
+            last_error.as_deref(),
+            Some("Failed to publish event: broker unavailable")
+        );
+
+        assert_eq!(relay.relay_pending().await.unwrap(), 3);
+        assert_eq!(relay.relay_pending().await.unwrap(), 0);
+
+        let published = publisher.published.lock().unwrap();
+        let ids: Vec<u32> = published.iter().map(|event| event.todo_id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(published[0].event_type, "TodoDeleted");
+        assert_eq!(published[0].tenant_id, "acme");
+    }
+}
@@ -1,8 +1,11 @@
 use async_trait::async_trait;
 
 use axum::{
-    extract::{rejection::JsonRejection, FromRequest, FromRequestParts},
-    http::request::Parts,
+    extract::{rejection::JsonRejection, FromRequest, FromRequestParts, Query},
+    http::{
+        header::{HeaderName, IF_MATCH, IF_NONE_MATCH},
+        request::Parts,
+    },
     Json,
 };
 
@@ -10,6 +13,9 @@ use hyper::Request;
 use serde::de::DeserializeOwned;
 use validator::Validate;
 
+use crate::auth::Principal;
+use crate::model::{Identity, Role};
+
 pub struct Path<T>(pub T);
 
 #[async_trait]
@@ -30,6 +36,28 @@ where
     }
 }
 
+pub struct QueryExtractor<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for QueryExtractor<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = crate::error::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state).await?;
+        value
+            .validate()
+            .map_err(|e| crate::error::Error::InvalidQuery(e.to_string()))?;
+        Ok(Self(value))
+    }
+}
+
 pub struct JsonExtractor<T>(pub T);
 
 #[async_trait]
@@ -51,3 +79,172 @@ where
         Ok(JsonExtractor(value))
     }
 }
+
+// Entity tags listed in a conditional request header
+fn entity_tags(parts: &Parts, header: HeaderName) -> Vec<String> {
+    parts
+        .headers
+        .get_all(header)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+// Entity tags a write is conditioned on by `If-Match`, `None` for a missing header or `*`
+pub struct IfMatch(Option<Vec<String>>);
+
+impl IfMatch {
+    // Version of the todo the write is conditioned on. Stores check a single version, the first
+    // tag of the todo is taken. Tags of other todos and weak tags never match under the strong
+    // comparison `If-Match` requires.
+    pub fn version(&self, id: u32) -> Result<Option<u32>, crate::error::Error> {
+        let tags = match &self.0 {
+            Some(tags) => tags,
+            None => return Ok(None),
+        };
+
+        tags.iter()
+            .filter_map(|tag| {
+                let (tag_id, version) =
+                    tag.strip_prefix('"')?.strip_suffix('"')?.split_once('-')?;
+                Some((tag_id.parse::<u32>().ok()?, version.parse().ok()?))
+            })
+            .find(|(tag_id, _)| *tag_id == id)
+            .map(|(_, version)| Some(version))
+            .ok_or_else(|| {
+                crate::error::Error::PreconditionFailed(
+                    "If-Match does not match the current version".to_owned(),
+                )
+            })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = crate::error::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let tags = entity_tags(parts, IF_MATCH);
+        match tags.is_empty() || tags.iter().any(|tag| tag == "*") {
+            true => Ok(Self(None)),
+            false => Ok(Self(Some(tags))),
+        }
+    }
+}
+
+pub struct IfNoneMatch(Vec<String>);
+
+impl IfNoneMatch {
+    // Weak comparison as required for `If-None-Match`, `*` matches any current representation
+    pub fn matches(&self, etag: &str) -> bool {
+        self.0
+            .iter()
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/"))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfNoneMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = crate::error::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(Self(entity_tags(parts, IF_NONE_MATCH)))
+    }
+}
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+// Id of the last server-sent event a reconnecting client has seen
+pub struct LastEventId(pub Option<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for LastEventId
+where
+    S: Send + Sync,
+{
+    type Rejection = crate::error::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(Self(
+            parts
+                .headers
+                .get(LAST_EVENT_ID)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned),
+        ))
+    }
+}
+
+// Marks `X-User-Roles` as set by a gateway vouching for it, see `auth.trust_role_headers`
+#[derive(Clone, Copy)]
+pub struct TrustedRoleHeaders;
+
+// Caller the todos are scoped to. Authenticated callers are taken from their principal alone.
+// Otherwise the `X-User-Id` and `X-Tenant-Id` headers are trusted as they are set by the gateway
+// in front of the service. `X-User-Roles` only counts when marked as trusted, anyone could claim
+// the admin role with it otherwise. Callers without any known role are editors of their own todos.
+#[async_trait]
+impl<S> FromRequestParts<S> for Identity
+where
+    S: Send + Sync,
+{
+    type Rejection = crate::error::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+
+        let (identity, roles) = match parts.extensions.get::<Principal>() {
+            Some(principal) => (
+                Identity::new(&principal.subject, principal.tenant_id.as_deref()),
+                principal.roles.clone(),
+            ),
+            None => (
+                Identity::new(
+                    header("x-user-id").ok_or_else(|| {
+                        crate::error::Error::Unauthorized("Missing X-User-Id header".to_owned())
+                    })?,
+                    header("x-tenant-id"),
+                ),
+                header("x-user-roles")
+                    .filter(|_| parts.extensions.get::<TrustedRoleHeaders>().is_some())
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(|role| role.parse().ok())
+                    .collect::<Vec<Role>>(),
+            ),
+        };
+
+        match roles.is_empty() {
+            true => Ok(identity),
+            false => Ok(identity.with_roles(roles)),
+        }
+    }
+}
@@ -1,77 +1,522 @@
-use crate::error::HttpResult;
-use crate::extractors::{JsonExtractor, Path};
-use crate::model::{Todo, TodoInput};
+use crate::config::LogDirectives;
+use crate::config::StreamConfig;
+use crate::error::{Error, HttpResult};
+use crate::events::feed::FeedItem;
+use crate::extractors::{IfMatch, IfNoneMatch, JsonExtractor, LastEventId, Path, QueryExtractor};
+use crate::health::{CheckStatus, Readiness};
+use crate::lifecycle::Lifecycle;
+use crate::model::{
+    BatchMode, Identity, Todo, TodoBatch, TodoBatchResponse, TodoBatchResult, TodoEventsParams,
+    TodoInput, TodoListParams, TodoPage, TodoPatch,
+};
+use crate::openapi::{ApiDoc, REDOC_PAGE};
+use crate::policy::authorize_admin;
+use crate::request_id::RequestId;
+use crate::telemetry::{LogFilter, LogFilterDirectives};
 use crate::use_cases::TodoInputPortArc;
-use axum::{Extension, Json};
+use crate::webhooks::{DeliveryAttempt, Webhook, WebhookInput, WebhookStore};
+use crate::ws::Session;
+use axum::{
+    extract::WebSocketUpgrade,
+    http::header::ETAG,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use futures::{stream, Stream};
 use hyper::StatusCode;
 use serde_json::{json, Value};
-use tracing::debug;
+use std::{convert::Infallible, sync::Arc};
+use utoipa::OpenApi;
 
+// Commands carry a single todo, which is at most a few hundred bytes
+const MAX_WS_MESSAGE_SIZE: usize = 64 * 1024;
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "probes",
+    security(),
+    responses((status = 200, description = "The service is alive"))
+)]
 pub async fn healthz_handler() -> Json<Value> {
-    debug!("Calling healthz handler...");
     Json(json!({
         "status": "ok"
     }))
 }
 
-pub async fn readyz_handler() -> Json<Value> {
-    debug!("Calling readyz handler...");
-    Json(json!({
-        "status": "ok"
-    }))
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "probes",
+    security(),
+    responses(
+        (status = 200, description = "The service and its dependencies are ready"),
+        (status = 503, description = "The service is draining or a dependency is unavailable")
+    )
+)]
+pub async fn readyz_handler(
+    Extension(lifecycle): Extension<Lifecycle>,
+    Extension(readiness): Extension<Readiness>,
+) -> (StatusCode, Json<Value>) {
+    if lifecycle.is_draining() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "draining"
+            })),
+        );
+    }
+
+    let checks = readiness.run().await;
+    if checks
+        .iter()
+        .any(|check| check.status == CheckStatus::Error)
+    {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "unavailable",
+                "checks": checks
+            })),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "ok",
+            "checks": checks
+        })),
+    )
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/todos",
+    tag = "todos",
+    params(TodoListParams),
+    responses(
+        (status = 200, description = "A page of todos", body = TodoPage),
+        (status = 400, description = "Invalid query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller may not list todos", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
 pub async fn list_todos_handler(
     Extension(todo_port): Extension<TodoInputPortArc>,
-) -> HttpResult<Json<Vec<Todo>>> {
-    debug!("Calling list_todo handler...");
-
-    let todos = todo_port.list_todos().await?;
+    identity: Identity,
+    QueryExtractor(params): QueryExtractor<TodoListParams>,
+) -> HttpResult<Json<TodoPage>> {
+    let todos = todo_port.list_todos(&identity, params.try_into()?).await?;
 
     Ok(Json(todos))
 }
 
+// Every change is sent with its id and type (`TodoCreated`, `TodoUpdated`, `TodoClosed` or
+// `TodoDeleted`). A `resync` event tells the client that changes were lost and the list has to be
+// reloaded, which happens when resuming from an id no longer buffered or when reading too slowly.
+#[utoipa::path(
+    get,
+    path = "/api/v1/todos/events",
+    tag = "todos",
+    params(
+        TodoEventsParams,
+        ("Last-Event-ID" = Option<String>, Header, description = "Id of the last event seen, the stream resumes after it")
+    ),
+    responses(
+        (status = 200, description = "Stream of todo changes", content_type = "text/event-stream"),
+        (status = 400, description = "Invalid query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller may not list todos", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn todo_events_handler(
+    Extension(todo_port): Extension<TodoInputPortArc>,
+    Extension(stream_config): Extension<StreamConfig>,
+    identity: Identity,
+    LastEventId(last_event_id): LastEventId,
+    QueryExtractor(params): QueryExtractor<TodoEventsParams>,
+) -> HttpResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    let subscription = todo_port
+        .watch_todos(&identity, params.state, last_event_id.as_deref())
+        .await?;
+
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let event = match subscription.next().await? {
+            FeedItem::Change(change) => Event::default()
+                .id(change.id.to_string())
+                .event(change.event.event_type())
+                .data(change.event.data().to_string()),
+            FeedItem::Missed => Event::default().event("resync").data("{}"),
+        };
+        Some((Ok(event), subscription))
+    });
+
+    Ok(Sse::new(events).keep_alive(
+        KeepAlive::new()
+            .interval(stream_config.heartbeat_interval)
+            .text("heartbeat"),
+    ))
+}
+
+// Frames are JSON objects tagged by `type`. Clients send `subscribe` (optional `state` and
+// `last_event_id`), `unsubscribe`, `create` (`todo`), `update` (`id`, `todo`, optional
+// `version`) and `delete` (`id`, optional `version`), every frame may carry a `ref` which is
+// echoed in its reply. The server replies with `subscribed`, `unsubscribed`, `result` (`todo`) or `error` (`problem`)
+// and streams `event` frames (`id`, `event`, `data`), or `resync` once changes were lost.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ws",
+    tag = "todos",
+    responses(
+        (status = 101, description = "Switched to the websocket protocol"),
+        (status = 400, description = "Not a websocket handshake", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn ws_handler(
+    Extension(todo_port): Extension<TodoInputPortArc>,
+    Extension(stream_config): Extension<StreamConfig>,
+    identity: Identity,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let session = Session::new(todo_port, identity);
+    let request_id = RequestId::current();
+
+    upgrade
+        .max_message_size(MAX_WS_MESSAGE_SIZE)
+        .on_upgrade(move |socket| async move {
+            let session = session.run(socket, stream_config);
+            match request_id {
+                Some(request_id) => request_id.scope(session).await,
+                None => session.await,
+            }
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/todos/{id}",
+    tag = "todos",
+    params(
+        ("id" = u32, Path, description = "Todo id"),
+        ("If-None-Match" = Option<String>, Header, description = "Entity tags of cached copies")
+    ),
+    responses(
+        (status = 200, description = "The todo", body = Todo, headers(("ETag" = String))),
+        (status = 304, description = "The cached copy is still current"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The todo does not exist", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
 pub async fn get_todo_handler(
     Path(id): Path<u32>,
     Extension(todo_port): Extension<TodoInputPortArc>,
-) -> HttpResult<Json<Todo>> {
-    debug!("Calling get_todo handler...");
+    identity: Identity,
+    if_none_match: IfNoneMatch,
+) -> HttpResult<Response> {
+    let todo = todo_port.get_todo(&identity, id).await?;
 
-    let todo = todo_port.get_todo(id).await?;
-
-    Ok(Json(todo))
+    if if_none_match.matches(&todo.etag()) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, todo.etag())]).into_response());
+    }
+    Ok(with_etag(todo).into_response())
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/todos",
+    tag = "todos",
+    request_body = TodoInput,
+    responses(
+        (status = 200, description = "The created todo", body = Todo, headers(("ETag" = String))),
+        (status = 400, description = "Invalid todo", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller may not create todos", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
 pub async fn create_todo_handler(
     Extension(todo_port): Extension<TodoInputPortArc>,
+    identity: Identity,
     JsonExtractor(todo_create): JsonExtractor<TodoInput>,
-) -> HttpResult<Json<Todo>> {
-    debug!("Calling create_todo handler...");
+) -> HttpResult<impl IntoResponse> {
+    let todo = todo_port.create_todo(&identity, todo_create).await?;
 
-    let todo = todo_port.create_todo(todo_create).await?;
+    Ok(with_etag(todo))
+}
 
-    Ok(Json(todo))
+// Runs the operations in a single transaction. All-or-nothing batches (the default) are rolled
+// back as a whole when an operation fails, best-effort batches only skip the failed ones.
+#[utoipa::path(
+    post,
+    path = "/api/v1/todos/batch",
+    tag = "todos",
+    request_body = TodoBatch,
+    responses(
+        (status = 200, description = "Results of the operations, in their order", body = TodoBatchResponse),
+        (status = 400, description = "Malformed batch or more than 100 operations", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn batch_todos_handler(
+    Extension(todo_port): Extension<TodoInputPortArc>,
+    identity: Identity,
+    JsonExtractor(batch): JsonExtractor<TodoBatch>,
+) -> HttpResult<Json<TodoBatchResponse>> {
+    let results = todo_port
+        .batch_todos(&identity, batch.mode, batch.operations)
+        .await?;
+
+    Ok(Json(TodoBatchResponse {
+        committed: batch.mode == BatchMode::BestEffort || results.iter().all(Result::is_ok),
+        results: results
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| TodoBatchResult::new(index, result))
+            .collect(),
+    }))
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/v1/todos/{id}",
+    tag = "todos",
+    params(
+        ("id" = u32, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag the update is conditioned on")
+    ),
+    request_body = TodoInput,
+    responses(
+        (status = 200, description = "The updated todo", body = Todo, headers(("ETag" = String))),
+        (status = 400, description = "Invalid todo", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller may not update todos", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The todo does not exist", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 412, description = "The todo has been modified", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
 pub async fn update_todo_handler(
     Path(id): Path<u32>,
     Extension(todo_port): Extension<TodoInputPortArc>,
+    if_match: IfMatch,
+    identity: Identity,
     JsonExtractor(todo_update): JsonExtractor<TodoInput>,
-) -> HttpResult<Json<Todo>> {
-    debug!("Calling update_todo handler...");
+) -> HttpResult<impl IntoResponse> {
+    let todo = todo_port
+        .update_todo(&identity, id, todo_update, if_match.version(id)?)
+        .await?;
 
-    let todo = todo_port.update_todo(id, todo_update).await?;
+    Ok(with_etag(todo))
+}
+
+// Accepts `application/merge-patch+json` (RFC 7396) as well as plain `application/json`
+#[utoipa::path(
+    patch,
+    path = "/api/v1/todos/{id}",
+    tag = "todos",
+    params(
+        ("id" = u32, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag the update is conditioned on")
+    ),
+    request_body(content = TodoPatch, content_type = "application/merge-patch+json"),
+    responses(
+        (status = 200, description = "The patched todo", body = Todo, headers(("ETag" = String))),
+        (status = 400, description = "Invalid patch", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller may not update todos", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The todo does not exist", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 412, description = "The todo has been modified", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn patch_todo_handler(
+    Path(id): Path<u32>,
+    Extension(todo_port): Extension<TodoInputPortArc>,
+    if_match: IfMatch,
+    identity: Identity,
+    JsonExtractor(todo_patch): JsonExtractor<TodoPatch>,
+) -> HttpResult<impl IntoResponse> {
+    let todo = todo_port
+        .patch_todo(&identity, id, todo_patch, if_match.version(id)?)
+        .await?;
 
-    Ok(Json(todo))
+    Ok(with_etag(todo))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/v1/todos/{id}",
+    tag = "todos",
+    params(
+        ("id" = u32, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag the delete is conditioned on")
+    ),
+    responses(
+        (status = 204, description = "The todo is gone, deleting a missing todo is a no-op"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller may not delete todos", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 412, description = "The todo has been modified", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
 pub async fn delete_todo_handler(
     Path(id): Path<u32>,
     Extension(todo_port): Extension<TodoInputPortArc>,
+    if_match: IfMatch,
+    identity: Identity,
 ) -> HttpResult<StatusCode> {
-    debug!("Calling create_todo handler...");
+    todo_port
+        .delete_todo(&identity, id, if_match.version(id)?)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    todo_port.delete_todo(id).await?;
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/log-filter",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Directives currently filtering the log lines", body = LogFilterDirectives),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller is not an admin", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn get_log_filter_handler(
+    Extension(log_filter): Extension<LogFilter>,
+    identity: Identity,
+) -> HttpResult<Json<LogFilterDirectives>> {
+    authorize_admin(&identity)?;
+
+    Ok(Json(LogFilterDirectives {
+        directives: log_filter.current().to_string(),
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/admin/log-filter",
+    tag = "admin",
+    request_body = LogFilterDirectives,
+    responses(
+        (status = 200, description = "The new directives filter the log lines from now on", body = LogFilterDirectives),
+        (status = 400, description = "The directives are invalid", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller is not an admin", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn update_log_filter_handler(
+    Extension(log_filter): Extension<LogFilter>,
+    identity: Identity,
+    JsonExtractor(update): JsonExtractor<LogFilterDirectives>,
+) -> HttpResult<Json<LogFilterDirectives>> {
+    authorize_admin(&identity)?;
+    let directives: LogDirectives = update.directives.parse().map_err(Error::InvalidPayload)?;
+
+    log_filter
+        .reload(directives, &identity.user_id)
+        .map_err(|e| Error::Unexpected(e.into()))?;
+
+    Ok(Json(LogFilterDirectives {
+        directives: log_filter.current().to_string(),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    request_body = WebhookInput,
+    responses(
+        (status = 200, description = "The registered webhook along with the secret signing its deliveries", body = Webhook),
+        (status = 400, description = "Invalid webhook", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller is not an admin", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn create_webhook_handler(
+    Extension(webhooks): Extension<Arc<WebhookStore>>,
+    identity: Identity,
+    JsonExtractor(input): JsonExtractor<WebhookInput>,
+) -> HttpResult<Json<Webhook>> {
+    authorize_admin(&identity)?;
+
+    Ok(Json(webhooks.create(&identity, &input).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "The webhooks of the tenant", body = [Webhook]),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller is not an admin", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn list_webhooks_handler(
+    Extension(webhooks): Extension<Arc<WebhookStore>>,
+    identity: Identity,
+) -> HttpResult<Json<Vec<Webhook>>> {
+    authorize_admin(&identity)?;
+
+    Ok(Json(webhooks.list(&identity).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = u32, Path, description = "Webhook id")),
+    responses(
+        (status = 204, description = "The webhook is gone along with its pending deliveries"),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The webhook does not exist", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn delete_webhook_handler(
+    Path(id): Path<u32>,
+    Extension(webhooks): Extension<Arc<WebhookStore>>,
+    identity: Identity,
+) -> HttpResult<StatusCode> {
+    authorize_admin(&identity)?;
+    webhooks.delete(&identity, id).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    params(("id" = u32, Path, description = "Webhook id")),
+    responses(
+        (status = 200, description = "The latest delivery attempts, newest first", body = [DeliveryAttempt]),
+        (status = 401, description = "The caller is not authenticated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The webhook does not exist", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+pub async fn list_webhook_deliveries_handler(
+    Path(id): Path<u32>,
+    Extension(webhooks): Extension<Arc<WebhookStore>>,
+    identity: Identity,
+) -> HttpResult<Json<Vec<DeliveryAttempt>>> {
+    authorize_admin(&identity)?;
+
+    Ok(Json(webhooks.attempts(&identity, id).await?))
+}
+
+fn with_etag(todo: Todo) -> impl IntoResponse {
+    ([(ETAG, todo.etag())], Json(todo))
+}
+
+pub async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub async fn api_docs_handler() -> Html<&'static str> {
+    Html(REDOC_PAGE)
+}
@@ -0,0 +1,178 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde_derive::Serialize;
+
+use crate::database::DatabasePool;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// Upper bound for a single check, a hanging dependency should not hang the probe
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type HealthCheckArc = Arc<dyn HealthCheck + Send + Sync>;
+
+// A dependency the service needs in order to serve traffic
+#[async_trait]
+pub trait HealthCheck {
+    fn name(&self) -> &str;
+    async fn check(&self) -> anyhow::Result<()>;
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckReport {
+    pub name: String,
+    pub status: CheckStatus,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Readiness {
+    checks: Arc<Vec<HealthCheckArc>>,
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<HealthCheckArc>) -> Self {
+        Self {
+            checks: Arc::new(checks),
+        }
+    }
+
+    // Runs all checks concurrently
+    pub async fn run(&self) -> Vec<CheckReport> {
+        join_all(self.checks.iter().map(|check| run_check(check.as_ref()))).await
+    }
+}
+
+async fn run_check(check: &(dyn HealthCheck + Send + Sync)) -> CheckReport {
+    let started = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check.check()).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("timed out after {:?}", CHECK_TIMEOUT)),
+    };
+
+    CheckReport {
+        name: check.name().to_owned(),
+        status: match result {
+            Ok(_) => CheckStatus::Ok,
+            Err(_) => CheckStatus::Error,
+        },
+        latency_ms: started.elapsed().as_millis(),
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+pub struct DatabasePingCheck {
+    pool: DatabasePool,
+}
+
+impl DatabasePingCheck {
+    pub fn new(pool: DatabasePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for DatabasePingCheck {
+    fn name(&self) -> &str {
+        self.pool.backend()
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        self.pool.ping().await?;
+        Ok(())
+    }
+}
+
+// Verifies every migration known to this binary has been applied to the database
+pub struct MigrationsCheck {
+    pool: DatabasePool,
+}
+
+impl MigrationsCheck {
+    pub fn new(pool: DatabasePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for MigrationsCheck {
+    fn name(&self) -> &str {
+        "migrations"
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let applied = self.pool.applied_migrations().await?;
+
+        let pending: Vec<String> = self
+            .pool
+            .migrator()
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration() && !applied.contains(&m.version))
+            .map(|m| format!("{}_{}", m.version, m.description))
+            .collect();
+
+        if !pending.is_empty() {
+            anyhow::bail!("pending migrations: {}", pending.join(", "));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use sqlx::sqlite::SqlitePool;
+
+    struct FailingCheck;
+
+    #[async_trait]
+    impl HealthCheck for FailingCheck {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        async fn check(&self) -> anyhow::Result<()> {
+            anyhow::bail!("dependency is down")
+        }
+    }
+
+    #[tokio::test]
+    async fn run_should_report_every_check() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let readiness = Readiness::new(vec![
+            Arc::new(DatabasePingCheck::new(DatabasePool::Sqlite(pool))),
+            Arc::new(FailingCheck),
+        ]);
+
+        let reports = readiness.run().await;
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].status, CheckStatus::Ok);
+        assert_eq!(reports[1].status, CheckStatus::Error);
+        assert_eq!(reports[1].error, Some("dependency is down".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn migrations_check_should_fail_until_migrations_run() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let check = MigrationsCheck::new(DatabasePool::Sqlite(pool.clone()));
+
+        assert!(check.check().await.is_err());
+
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        assert!(check.check().await.is_ok());
+    }
+}
@@ -1,27 +1,89 @@
+mod access_log;
+mod auth;
+mod cli;
 mod config;
+mod database;
 mod error;
+mod events;
 mod extractors;
 mod handlers;
+mod health;
+mod lifecycle;
+mod metrics;
 mod model;
+mod openapi;
+mod policy;
+mod request_id;
 mod server;
+mod telemetry;
 mod todo_store;
 mod use_cases;
+mod webhooks;
+mod ws;
 
-use std::{error::Error, str::FromStr};
-use tracing::{debug, error, info, trace, Level};
+use clap::Parser;
+use std::error::Error;
+use tracing::{debug, error, info, trace};
 
+use auth::ApiKeyStore;
+use cli::{ApiKeyCommand, Cli, Command};
 use config::Config;
+use database::DatabasePool;
 use server::init_http_server;
+use telemetry::init_telemetry;
 
-// TODO: move this into service utils
-fn init_logger(config: &Config) -> Result<(), Box<dyn Error>> {
-    let subscriber =
-        tracing_subscriber::fmt().with_max_level(Level::from_str(&config.logger.level)?);
+// Maintenance commands print to stdout, they run before the logger is set up
+async fn run_command(config: &Config, command: &Command) -> Result<(), Box<dyn Error>> {
+    let pool = match DatabasePool::migrate(&config.database).await? {
+        DatabasePool::Sqlite(pool) => pool,
+        DatabasePool::Postgres(_) => return Err("API keys require a SQLite database".into()),
+    };
+    let api_keys = ApiKeyStore::new(pool);
 
-    if config.logger.format == "json" {
-        subscriber.json().init();
-    } else {
-        subscriber.compact().with_ansi(true).init();
+    match command {
+        Command::ApiKey(ApiKeyCommand::Create {
+            name,
+            subject,
+            tenant,
+            scopes,
+            expires_in,
+        }) => {
+            let expires_at = expires_in
+                .map(|expires_in| chrono::Duration::from_std(expires_in.into()))
+                .transpose()?
+                .map(|expires_in| chrono::Utc::now() + expires_in);
+            let (key, secret) = api_keys
+                .create(name, subject, tenant.as_deref(), scopes, expires_at)
+                .await?;
+            println!("Created API key {} for '{}'", key.id, key.subject);
+            println!("{}", secret);
+        }
+        Command::ApiKey(ApiKeyCommand::List) => {
+            for key in api_keys.list().await? {
+                let status = match (key.revoked_at, key.expires_at) {
+                    (Some(revoked_at), _) => format!("revoked {}", revoked_at),
+                    (None, Some(expires_at)) if expires_at <= chrono::Utc::now() => {
+                        format!("expired {}", expires_at)
+                    }
+                    (None, Some(expires_at)) => format!("expires {}", expires_at),
+                    (None, None) => "active".to_owned(),
+                };
+                let scopes: Vec<String> = key.scopes.iter().map(ToString::to_string).collect();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    key.id,
+                    key.name,
+                    key.subject,
+                    key.tenant_id.as_deref().unwrap_or("-"),
+                    scopes.join(","),
+                    status
+                );
+            }
+        }
+        Command::ApiKey(ApiKeyCommand::Revoke { id }) => match api_keys.revoke(id).await? {
+            true => println!("Revoked API key {}", id),
+            false => return Err(format!("No active API key with id {}", id).into()),
+        },
     }
 
     Ok(())
@@ -29,9 +91,19 @@ fn init_logger(config: &Config) -> Result<(), Box<dyn Error>> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::new()?;
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
 
-    init_logger(&config)?;
+    if let Some(command) = &cli.command {
+        return run_command(&config, command).await;
+    }
+
+    let telemetry = init_telemetry(&config)?;
+    #[cfg(unix)]
+    tokio::spawn(telemetry::reload_log_filter_on_sighup(
+        cli.clone(),
+        telemetry.log_filter.clone(),
+    ));
     info!("Loaded configuration: {:?}", &config);
 
     // demonstrate logger usage
@@ -40,7 +112,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     trace!("This is a trace log");
 
     info!("Initializing http server...");
-    init_http_server(&config).await?.await?;
+    init_http_server(&config, telemetry.log_filter)
+        .await?
+        .serve()
+        .await?;
+
+    // Flushes the spans which have not been exported yet
+    drop(telemetry.tracer_provider);
 
     Ok(())
 }
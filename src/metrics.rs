@@ -0,0 +1,210 @@
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header::CONTENT_TYPE, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use hyper::StatusCode;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+use crate::database::DatabasePool;
+
+// Requests which did not match any route share a single label value to bound the cardinality
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// RED metrics of the http api and the todo store, along with the database pool statistics
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    requests_in_flight: IntGaugeVec,
+    store_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    pool: Option<DatabasePool>,
+}
+
+impl Metrics {
+    pub fn new(pool: Option<DatabasePool>) -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of handled http requests"),
+            &["method", "route", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling http requests",
+            ),
+            &["method", "route"],
+        )?;
+        let requests_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "http_requests_in_flight",
+                "Number of http requests being handled",
+            ),
+            &["method", "route"],
+        )?;
+        let store_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "store_operation_duration_seconds",
+                "Time spent in todo store operations",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+            ]),
+            &["operation", "outcome"],
+        )?;
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Connections of the database pool by state",
+            ),
+            &["backend", "state"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(requests_in_flight.clone()))?;
+        registry.register(Box::new(store_duration.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            requests_in_flight,
+            store_duration,
+            pool_connections,
+            pool,
+        })
+    }
+
+    pub fn observe_store_operation(&self, operation: &str, success: bool, elapsed: Duration) {
+        let outcome = if success { "ok" } else { "error" };
+        self.store_duration
+            .with_label_values(&[operation, outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // Pool statistics are sampled when scraped
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        if let Some(pool) = &self.pool {
+            let (size, idle) = pool.connection_stats();
+            let gauge = |state| {
+                self.pool_connections
+                    .with_label_values(&[pool.backend(), state])
+            };
+            gauge("idle").set(idle as i64);
+            gauge("in_use").set(i64::from(size) - idle as i64);
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+// Decrements the in-flight gauge even when the request future is dropped
+struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Middleware recording the RED metrics, labelled by the route template rather than the raw path.
+// Use with `from_fn_with_state` as a router layer so that the matched path is known.
+pub async fn track_requests<B>(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_owned());
+    let method = request.method().to_string();
+
+    let in_flight = metrics
+        .requests_in_flight
+        .with_label_values(&[&method, &route]);
+    in_flight.inc();
+    let _in_flight = InFlight(in_flight);
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    metrics
+        .request_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> Response {
+    match metrics.render() {
+        Ok(body) => ([(CONTENT_TYPE, TextEncoder::new().format_type())], body).into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Served on its own listener, the metrics are not part of the public api
+pub fn metrics_router(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn track_requests_should_label_by_route_template() {
+        let metrics = Arc::new(Metrics::new(None).unwrap());
+        let app = Router::new()
+            .route("/todos/:id", get(|| async { "todo" }))
+            .layer(middleware::from_fn_with_state(
+                metrics.clone(),
+                track_requests,
+            ));
+
+        for uri in ["/todos/7", "/todos/8", "/missing"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered
+            .contains(r#"http_requests_total{method="GET",route="/todos/:id",status="200"} 2"#));
+        assert!(rendered
+            .contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+        assert!(rendered
+            .contains(r#"http_request_duration_seconds_count{method="GET",route="/todos/:id"} 2"#));
+        assert!(rendered.contains(r#"http_requests_in_flight{method="GET",route="/todos/:id"} 0"#));
+        assert!(!rendered.contains("/todos/7"));
+    }
+}
@@ -1,27 +1,448 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use http_api_problem::{ApiError, HttpApiProblem};
+use serde::{Deserializer, Serialize as _, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use sqlx::{FromRow, Type};
-use validator::Validate;
+use std::{fmt, str::FromStr};
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidationError, ValidationErrors};
 
-#[derive(Serialize, Deserialize, Clone, Type, Debug, PartialEq)]
+use crate::error::Error;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 100;
+pub const MAX_BATCH_SIZE: usize = 100;
+
+// Caller the todos are scoped to, every todo belongs to a single owner within a tenant
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    pub tenant_id: Option<String>,
+    pub roles: Vec<Role>,
+}
+
+impl Identity {
+    // Callers without explicit roles are editors of their own todos
+    pub fn new(user_id: &str, tenant_id: Option<&str>) -> Self {
+        Self {
+            user_id: user_id.to_owned(),
+            tenant_id: tenant_id.map(str::to_owned),
+            roles: vec![Role::Editor],
+        }
+    }
+
+    pub fn with_roles(self, roles: Vec<Role>) -> Self {
+        Self { roles, ..self }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Editor,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "reader" => Ok(Role::Reader),
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("Unknown role '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Reader => "reader",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+// Todos an operation may reach within the caller's tenant, granted by the authorization policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Own,
+    Tenant,
+}
+
+#[derive(Serialize, Deserialize, Clone, Type, Debug, PartialEq, ToSchema)]
+#[sqlx(type_name = "todo_state")]
 pub enum TodoState {
     Opened,
     Closed,
 }
 
-#[derive(Serialize, Clone, FromRow, Debug, PartialEq)]
+#[derive(Serialize, Clone, FromRow, Debug, PartialEq, ToSchema)]
 pub struct Todo {
     pub id: u32,
     pub text: String,
     pub state: TodoState,
+    pub version: u32,
+    // Audit fields are maintained by the stores, `closed_at` is only set while the todo is closed
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub closed_at: Option<DateTime<Utc>>,
+    pub created_by: Option<String>,
+    pub updated_by: Option<String>,
+    // User the todo belongs to, not necessarily its creator, e.g. for adopted legacy todos.
+    // It scopes the todo and its changes and is not part of the api.
+    #[serde(skip)]
+    pub owner_id: String,
+}
+
+impl Todo {
+    // Strong entity tag derived from the id and the version, which the stores bump on every
+    // write. Ids are never reused, a tag can not match another todo.
+    pub fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.id, self.version)
+    }
 }
 
-#[derive(Deserialize, Clone, Validate)]
+// Schema constraints mirror the validation rules, the spec test keeps them in sync
+#[derive(Deserialize, Clone, Validate, ToSchema)]
 pub struct TodoInput {
     #[validate(length(
         min = 1,
         max = 200,
         message = "Can not be empty or longer then 200 characters"
     ))]
+    #[schema(min_length = 1, max_length = 200)]
     pub text: String,
     pub state: TodoState,
 }
+
+// Partial update following JSON Merge Patch (RFC 7396) semantics: absent fields are left
+// untouched. Both fields are mandatory on a todo, so removing them with `null` is rejected.
+// The stored todo is always valid, which makes validating the supplied fields equivalent
+// to validating the merged result.
+#[derive(Deserialize, Clone, Validate, Default, Debug, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TodoPatch {
+    #[serde(default, deserialize_with = "deserialize_present")]
+    #[validate(length(
+        min = 1,
+        max = 200,
+        message = "Can not be empty or longer then 200 characters"
+    ))]
+    #[schema(min_length = 1, max_length = 200, nullable = false)]
+    pub text: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    #[schema(nullable = false)]
+    pub state: Option<TodoState>,
+}
+
+// Single write of a batch, validated and authorized like the equivalent single request
+#[derive(Deserialize, Clone, ToSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TodoOperation {
+    Create {
+        todo: TodoInput,
+    },
+    Update {
+        id: u32,
+        todo: TodoInput,
+        /// Version the update is conditioned on, like `If-Match`
+        version: Option<u32>,
+    },
+    Delete {
+        id: u32,
+        /// Version the delete is conditioned on, like `If-Match`
+        version: Option<u32>,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    // Either every operation is applied or none
+    #[default]
+    AllOrNothing,
+    // Operations which fail are skipped, the others are applied
+    BestEffort,
+}
+
+// Body of POST /api/v1/todos/batch. The operations are validated one by one,
+// an invalid operation fails on its own rather than the whole request.
+#[derive(Deserialize, Clone, ToSchema)]
+pub struct TodoBatch {
+    #[serde(default)]
+    pub mode: BatchMode,
+    #[schema(min_items = 1, max_items = 100)]
+    pub operations: Vec<TodoOperation>,
+}
+
+// Only the size is checked up front, the derive would copy the operations into the error
+impl Validate for TodoBatch {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        if !(1..=MAX_BATCH_SIZE).contains(&self.operations.len()) {
+            let mut error = ValidationError::new("length");
+            error.message = Some("Must hold between 1 and 100 operations".into());
+            errors.add("operations", error);
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+// Updated todo along with the state it was in before, read by the store within the write.
+// It tells closing a todo from editing an already closed one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpdatedTodo {
+    pub previous_state: TodoState,
+    pub todo: Todo,
+}
+
+// Outcome of an operation of a batch, deleting a missing todo is a no-op
+#[derive(Clone, Debug, PartialEq)]
+pub enum TodoWritten {
+    Created(Todo),
+    Updated(UpdatedTodo),
+    Deleted(Option<Todo>),
+}
+
+impl TodoWritten {
+    // The todo as written, the last state of a deleted one
+    pub fn todo(&self) -> Option<&Todo> {
+        match self {
+            TodoWritten::Created(todo) => Some(todo),
+            TodoWritten::Updated(updated) => Some(&updated.todo),
+            TodoWritten::Deleted(todo) => todo.as_ref(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TodoBatchResult {
+    /// Position of the operation in the request
+    pub index: usize,
+    /// Status the operation would have gotten as a single request
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub todo: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<crate::openapi::ProblemDetails>)]
+    pub error: Option<HttpApiProblem>,
+}
+
+impl TodoBatchResult {
+    pub fn new(index: usize, result: Result<TodoWritten, Error>) -> Self {
+        match result {
+            Ok(TodoWritten::Created(todo)) | Ok(TodoWritten::Updated(UpdatedTodo { todo, .. })) => {
+                Self {
+                    index,
+                    status: 200,
+                    todo: Some(todo),
+                    error: None,
+                }
+            }
+            Ok(TodoWritten::Deleted(_)) => Self {
+                index,
+                status: 204,
+                todo: None,
+                error: None,
+            },
+            Err(error) => Self {
+                index,
+                status: error.status_code().as_u16(),
+                todo: None,
+                error: Some(ApiError::from(error).into_http_api_problem()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TodoBatchResponse {
+    /// Whether the successful operations were applied, always the case for best-effort batches
+    pub committed: bool,
+    pub results: Vec<TodoBatchResult>,
+}
+
+// Results of an all-or-nothing batch whose operation at `failed` failed: that operation keeps
+// its error, all the others are reported as not applied
+pub fn aborted_batch(len: usize, failed: usize, error: Error) -> Vec<Result<TodoWritten, Error>> {
+    let mut results: Vec<_> = (0..len).map(|_| Err(Error::batch_aborted())).collect();
+    if let Some(result) = results.get_mut(failed) {
+        *result = Err(error);
+    }
+    results
+}
+
+fn deserialize_present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default, ToSchema)]
+pub enum TodoSort {
+    #[default]
+    #[serde(rename = "id")]
+    IdAsc,
+    #[serde(rename = "-id")]
+    IdDesc,
+    #[serde(rename = "text")]
+    TextAsc,
+}
+
+// Query string of GET /api/v1/todos
+#[derive(Deserialize, Validate, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TodoListParams {
+    /// Page size, defaults to 50
+    #[validate(range(min = 1, max = 100, message = "Must be between 1 and 100"))]
+    #[param(minimum = 1, maximum = 100)]
+    pub limit: Option<u32>,
+    /// Opaque `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    pub state: Option<TodoState>,
+    /// Case sensitive substring the text has to contain
+    #[validate(length(max = 200, message = "Can not be longer then 200 characters"))]
+    #[param(max_length = 200)]
+    pub q: Option<String>,
+    pub sort: Option<TodoSort>,
+}
+
+// Query string of GET /api/v1/todos/events
+#[derive(Deserialize, Validate, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TodoEventsParams {
+    /// Only changes leaving the todo in this state, deletes carry the last state
+    pub state: Option<TodoState>,
+}
+
+// Position after the last item of a page (keyset pagination).
+// Clients only see it as an opaque string.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TodoCursor {
+    pub sort: TodoSort,
+    pub id: u32,
+    pub text: String,
+}
+
+impl TodoCursor {
+    pub fn after(todo: &Todo, sort: TodoSort) -> Self {
+        Self {
+            sort,
+            id: todo.id,
+            text: todo.text.clone(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(value: &str) -> Result<Self, Error> {
+        URL_SAFE_NO_PAD
+            .decode(value)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| Error::InvalidQuery("Malformed cursor".to_owned()))
+    }
+}
+
+fn serialize_cursor<S: Serializer>(
+    cursor: &Option<TodoCursor>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    cursor
+        .as_ref()
+        .map(TodoCursor::encode)
+        .serialize(serializer)
+}
+
+// Store level query, every TodoOutputPort implements the same semantics:
+// `text_contains` is a case sensitive substring match, text is ordered bytewise
+// and ties are broken by id
+#[derive(Clone, Debug, PartialEq)]
+pub struct TodoQuery {
+    pub limit: u32,
+    pub after: Option<TodoCursor>,
+    pub state: Option<TodoState>,
+    pub text_contains: Option<String>,
+    pub sort: TodoSort,
+}
+
+impl Default for TodoQuery {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_PAGE_SIZE,
+            after: None,
+            state: None,
+            text_contains: None,
+            sort: TodoSort::default(),
+        }
+    }
+}
+
+impl TryFrom<TodoListParams> for TodoQuery {
+    type Error = Error;
+
+    fn try_from(params: TodoListParams) -> Result<Self, Self::Error> {
+        let sort = params.sort.unwrap_or_default();
+        let after = params
+            .cursor
+            .as_deref()
+            .map(TodoCursor::decode)
+            .transpose()?;
+
+        if matches!(&after, Some(cursor) if cursor.sort != sort) {
+            return Err(Error::InvalidQuery(
+                "Cursor was issued for a different sort order".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            limit: params.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE),
+            after,
+            state: params.state,
+            text_contains: params.q.filter(|q| !q.is_empty()),
+            sort,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct TodoPage {
+    pub items: Vec<Todo>,
+    #[serde(serialize_with = "serialize_cursor")]
+    #[schema(value_type = Option<String>)]
+    pub next_cursor: Option<TodoCursor>,
+    pub total_count: u64,
+}
+
+impl TodoPage {
+    // Stores fetch `limit + 1` rows, the extra row tells whether there is a next page
+    pub fn from_rows(mut rows: Vec<Todo>, query: &TodoQuery, total_count: u64) -> Self {
+        let next_cursor = if rows.len() > query.limit as usize {
+            rows.truncate(query.limit as usize);
+            rows.last().map(|todo| TodoCursor::after(todo, query.sort))
+        } else {
+            None
+        };
+
+        Self {
+            items: rows,
+            next_cursor,
+            total_count,
+        }
+    }
+}
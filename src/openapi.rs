@@ -0,0 +1,183 @@
+use serde_derive::Serialize;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::handlers;
+use crate::model::{
+    BatchMode, Todo, TodoBatch, TodoBatchResponse, TodoBatchResult, TodoInput, TodoOperation,
+    TodoPage, TodoPatch, TodoSort, TodoState,
+};
+use crate::telemetry::LogFilterDirectives;
+use crate::webhooks::{DeliveryAttempt, Webhook, WebhookInput};
+
+// Shape of every error response (RFC 7807), documentation only as errors are built by ApiError
+#[derive(Serialize, ToSchema)]
+pub struct ProblemDetails {
+    /// Error code in the form of `type://error.<category>.<kind>`
+    #[serde(rename = "type")]
+    pub type_url: String,
+    pub status: u16,
+    pub title: String,
+    pub detail: Option<String>,
+    /// Id of the request, as echoed in the `X-Request-Id` header
+    pub request_id: Option<String>,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Todo service",
+        description = "Sample todo service built with Axum. Without authentication the caller is \
+                       identified by the `X-User-Id`, `X-Tenant-Id` and `X-User-Roles` headers."
+    ),
+    paths(
+        handlers::healthz_handler,
+        handlers::readyz_handler,
+        handlers::list_todos_handler,
+        handlers::todo_events_handler,
+        handlers::ws_handler,
+        handlers::get_todo_handler,
+        handlers::create_todo_handler,
+        handlers::batch_todos_handler,
+        handlers::update_todo_handler,
+        handlers::patch_todo_handler,
+        handlers::delete_todo_handler,
+        handlers::get_log_filter_handler,
+        handlers::update_log_filter_handler,
+        handlers::create_webhook_handler,
+        handlers::list_webhooks_handler,
+        handlers::delete_webhook_handler,
+        handlers::list_webhook_deliveries_handler,
+    ),
+    components(schemas(
+        Todo,
+        TodoInput,
+        TodoPatch,
+        TodoPage,
+        TodoState,
+        TodoSort,
+        TodoBatch,
+        TodoOperation,
+        BatchMode,
+        TodoBatchResult,
+        TodoBatchResponse,
+        LogFilterDirectives,
+        WebhookInput,
+        Webhook,
+        DeliveryAttempt,
+        ProblemDetails
+    )),
+    modifiers(&SecuritySchemes),
+    security(("bearer_token" = []), ("api_key" = [])),
+    tags((name = "todos"), (name = "probes"), (name = "admin"), (name = "webhooks"))
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_token",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+    }
+}
+
+// Redoc renders the spec served next to it, the script is loaded from its CDN
+pub const REDOC_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Todo service API</title>
+    <meta charset="utf-8"/>
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+  </head>
+  <body>
+    <redoc spec-url="/api-docs/openapi.json"></redoc>
+    <script src="https://cdn.redoc.ly/redoc/latest/bundles/redoc.standalone.js"></script>
+  </body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    // Routes registered in server.rs, `:param` segments are converted to the `{param}` form
+    fn server_routes() -> BTreeSet<(String, String)> {
+        let source = include_str!("server.rs");
+        let mut routes = BTreeSet::new();
+
+        for call in source.split(".route(").skip(1) {
+            let mut parts = call.splitn(3, '"');
+            let (path, handler) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(_), Some(path), Some(rest)) => (path, rest),
+                _ => continue,
+            };
+            let method = handler
+                .trim_start_matches([',', ' ', '\n'])
+                .split('(')
+                .next()
+                .unwrap_or_default();
+            let path = path
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(param) => format!("{{{}}}", param),
+                    None => segment.to_owned(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+
+            routes.insert((path, method.to_owned()));
+        }
+
+        routes
+    }
+
+    #[test]
+    fn spec_should_document_every_route_of_the_server() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let documented: BTreeSet<(String, String)> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .map(move |method| (path.clone(), method.clone()))
+            })
+            .collect();
+        let served: BTreeSet<(String, String)> = server_routes()
+            .into_iter()
+            .filter(|(path, _)| !path.starts_with("/api-docs"))
+            .collect();
+
+        assert!(served.contains(&("/api/v1/todos/{id}".to_owned(), "patch".to_owned())));
+        assert_eq!(documented, served);
+    }
+
+    #[test]
+    fn spec_should_include_validation_constraints() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let text = &spec["components"]["schemas"]["TodoInput"]["properties"]["text"];
+
+        assert_eq!(text["minLength"], Value::from(1));
+        assert_eq!(text["maxLength"], Value::from(200));
+        assert!(spec["components"]["schemas"]["ProblemDetails"].is_object());
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use crate::error::{Error, Result};
+use crate::model::{Identity, Role, Scope};
+
+// Operations of the `TodoInputPort` subject to authorization
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    List,
+    Get,
+    Create,
+    Update,
+    Patch,
+    Delete,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::List => "list",
+            Operation::Get => "get",
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Patch => "patch",
+            Operation::Delete => "delete",
+        };
+        f.write_str(name)
+    }
+}
+
+// Grants the listed roles an operation over the todos within the given scope
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub operation: Operation,
+    pub roles: &'static [Role],
+    pub scope: Scope,
+}
+
+const fn rule(operation: Operation, roles: &'static [Role], scope: Scope) -> Rule {
+    Rule {
+        operation,
+        roles,
+        scope,
+    }
+}
+
+const ANYONE: &[Role] = &[Role::Reader, Role::Editor, Role::Admin];
+const WRITERS: &[Role] = &[Role::Editor, Role::Admin];
+const ADMINS: &[Role] = &[Role::Admin];
+
+// Readers only see their own todos, editors manage them and admins manage the whole tenant
+pub const DEFAULT_RULES: &[Rule] = &[
+    rule(Operation::List, ANYONE, Scope::Own),
+    rule(Operation::Get, ANYONE, Scope::Own),
+    rule(Operation::Create, WRITERS, Scope::Own),
+    rule(Operation::Update, WRITERS, Scope::Own),
+    rule(Operation::Patch, WRITERS, Scope::Own),
+    rule(Operation::Delete, WRITERS, Scope::Own),
+    rule(Operation::List, ADMINS, Scope::Tenant),
+    rule(Operation::Get, ADMINS, Scope::Tenant),
+    rule(Operation::Update, ADMINS, Scope::Tenant),
+    rule(Operation::Patch, ADMINS, Scope::Tenant),
+    rule(Operation::Delete, ADMINS, Scope::Tenant),
+];
+
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    // Widest scope any of the caller's roles is granted, everything not granted is forbidden
+    pub fn authorize(&self, identity: &Identity, operation: Operation) -> Result<Scope> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.operation == operation)
+            .filter(|rule| identity.roles.iter().any(|role| rule.roles.contains(role)))
+            .map(|rule| rule.scope)
+            .max()
+            .ok_or_else(|| {
+                Error::Forbidden(format!(
+                    "User '{}' is not allowed to {} todos",
+                    identity.user_id, operation
+                ))
+            })
+    }
+}
+
+// Operational endpoints are reserved to admins, independently of the todo rules
+pub fn authorize_admin(identity: &Identity) -> Result<()> {
+    match identity.roles.contains(&Role::Admin) {
+        true => Ok(()),
+        false => Err(Error::Forbidden(format!(
+            "User '{}' is not an admin",
+            identity.user_id
+        ))),
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new(DEFAULT_RULES.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize_should_grant_the_widest_scope_of_the_roles() {
+        let policy = Policy::default();
+        let reader = Identity::new("alice", None).with_roles(vec![Role::Reader]);
+        let admin = Identity::new("bob", None).with_roles(vec![Role::Reader, Role::Admin]);
+
+        assert_eq!(
+            policy.authorize(&reader, Operation::Get).unwrap(),
+            Scope::Own
+        );
+        assert!(matches!(
+            policy.authorize(&reader, Operation::Create),
+            Err(Error::Forbidden(_))
+        ));
+        assert_eq!(
+            policy.authorize(&admin, Operation::Delete).unwrap(),
+            Scope::Tenant
+        );
+        assert_eq!(
+            policy.authorize(&admin, Operation::Create).unwrap(),
+            Scope::Own
+        );
+        assert!(matches!(
+            policy.authorize(&admin.with_roles(vec![]), Operation::List),
+            Err(Error::Forbidden(_))
+        ));
+    }
+}
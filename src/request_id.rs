@@ -0,0 +1,128 @@
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::{fmt, future::Future};
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer or unusual ids supplied by the client are replaced, they end up in every log line
+const MAX_LENGTH: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    fn parse(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LENGTH
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+
+        valid.then(|| Self(value.to_owned()))
+    }
+
+    // Id of the request being handled by the current task, if any
+    pub fn current() -> Option<Self> {
+        REQUEST_ID.try_with(Clone::clone).ok()
+    }
+
+    // Runs work outliving the request, e.g. a websocket session, under the id of the request
+    pub async fn scope<F: Future>(self, work: F) -> F::Output {
+        REQUEST_ID.scope(self, work).await
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+tokio::task_local! {
+    // Errors are converted to Problem Details far away from the request, see `RequestId::current`
+    static REQUEST_ID: RequestId;
+}
+
+// Middleware accepting the `X-Request-Id` of the caller or generating a UUID, echoed in the
+// response. Install it as the outermost layer, the request span picks the id up from the
+// request extensions.
+pub async fn propagate_request_id<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(RequestId::parse)
+        .unwrap_or_else(RequestId::generate);
+    let header = HeaderValue::from_str(request_id.as_str()).expect("request ids are ascii");
+
+    request
+        .headers_mut()
+        .insert(X_REQUEST_ID.clone(), header.clone());
+    request.extensions_mut().insert(request_id.clone());
+
+    let mut response = REQUEST_ID.scope(request_id, next.run(request)).await;
+    response.headers_mut().insert(X_REQUEST_ID.clone(), header);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Error, HttpResult};
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn forbidden_handler() -> HttpResult<()> {
+        Err(Error::Forbidden("Nope".to_owned()).into())
+    }
+
+    async fn send(request_id: Option<&str>) -> (String, serde_json::Value) {
+        let app = Router::new()
+            .route("/forbidden", get(forbidden_handler))
+            .layer(middleware::from_fn(propagate_request_id));
+
+        let mut request = Request::builder().uri("/forbidden");
+        if let Some(request_id) = request_id {
+            request = request.header(&X_REQUEST_ID, request_id);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let echoed = response.headers()[&X_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (echoed, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn request_id_should_be_echoed_and_added_to_problem_details() {
+        let (echoed, problem) = send(Some("client-id.42")).await;
+        assert_eq!(echoed, "client-id.42");
+        assert_eq!(problem["request_id"], "client-id.42");
+
+        for supplied in [None, Some("spaces are not allowed"), Some("")] {
+            let (echoed, problem) = send(supplied).await;
+            assert!(Uuid::parse_str(&echoed).is_ok());
+            assert_eq!(problem["request_id"], echoed.as_str());
+        }
+
+        assert_eq!(RequestId::current(), None);
+    }
+}
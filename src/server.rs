@@ -1,64 +1,428 @@
 use crate::{
+    access_log::{log_requests, AccessLog},
+    auth::{authenticate, ApiKeyStore, Authenticator, JwtAuthenticator},
     config::Config,
+    database::DatabasePool,
+    events::feed::TodoFeed,
+    events::{
+        init_event_publisher,
+        outbox::{OutboxRelay, SqliteOutbox},
+        EventPublisherArc, FanOutPublisher,
+    },
+    extractors::TrustedRoleHeaders,
     handlers::{
-        create_todo_handler, delete_todo_handler, get_todo_handler, healthz_handler,
-        list_todos_handler, readyz_handler, update_todo_handler,
+        api_docs_handler, batch_todos_handler, create_todo_handler, create_webhook_handler,
+        delete_todo_handler, delete_webhook_handler, get_log_filter_handler, get_todo_handler,
+        healthz_handler, list_todos_handler, list_webhook_deliveries_handler,
+        list_webhooks_handler, openapi_handler, patch_todo_handler, readyz_handler,
+        todo_events_handler, update_log_filter_handler, update_todo_handler, ws_handler,
     },
-    use_cases::{TodoInputPortArc, TodoService},
+    health::{DatabasePingCheck, MigrationsCheck, Readiness},
+    lifecycle::{shutdown_signal, Lifecycle},
+    metrics::{metrics_router, track_requests, Metrics},
+    request_id::propagate_request_id,
+    telemetry::{trace_requests, LogFilter},
+    use_cases::{TodoInputPortArc, TodoOutputPortArc, TodoService},
+    webhooks::{delivery::WebhookWorker, WebhookStore},
 };
 
-use crate::todo_store::sqlite::SqliteTodoStore;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
-use std::str::FromStr;
+use crate::todo_store::{init_todo_store, metered::MeteredTodoStore, TodoStoreHandle};
 
 use hyper::server::conn::AddrIncoming;
-use std::{error::Error, sync::Arc};
+use std::{error::Error, sync::Arc, time::Duration};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
 
 use axum::{
-    routing::{delete, get, post, put, IntoMakeService},
+    extract::connect_info::IntoMakeServiceWithConnectInfo,
+    middleware,
+    routing::{delete, get, patch, post, put, IntoMakeService},
     Extension, Router, Server,
 };
 
 use std::net::SocketAddr;
 
-async fn init_sql_client() -> Result<SqlitePool, Box<dyn Error>> {
-    let connect_options = SqliteConnectOptions::from_str("sqlite://db/data.db")?
-        .create_if_missing(true)
-        .journal_mode(SqliteJournalMode::Wal);
+pub struct HttpServer {
+    server: Server<AddrIncoming, IntoMakeServiceWithConnectInfo<Router, SocketAddr>>,
+    metrics_server: Option<Server<AddrIncoming, IntoMakeService<Router>>>,
+    outbox_relay: Option<OutboxRelay>,
+    webhook_worker: Option<WebhookWorker>,
+    pool: Option<DatabasePool>,
+    lifecycle: Lifecycle,
+    pre_drain_delay: Duration,
+    drain_timeout: Duration,
+}
+
+impl HttpServer {
+    // Serves requests until SIGINT/SIGTERM is received. Readiness fails right away, the listener
+    // keeps accepting connections for `pre_drain_delay` so that the load balancer notices before
+    // it closes. In-flight requests then get up to `drain_timeout` to finish.
+    // The database pool is closed on every way out.
+    pub async fn serve(self) -> Result<(), Box<dyn Error>> {
+        // The metrics listener keeps running while draining so the shutdown stays observable
+        let metrics_server = self.metrics_server.map(tokio::spawn);
+        let outbox_relay = self.outbox_relay.map(|relay| tokio::spawn(relay.run()));
+        let webhook_worker = self.webhook_worker.map(|worker| tokio::spawn(worker.run()));
+
+        let (draining_tx, draining_rx) = oneshot::channel();
+        let lifecycle = self.lifecycle.clone();
+        let pre_drain_delay = self.pre_drain_delay;
+
+        let server = self.server.with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            info!(
+                "Reporting not ready, draining connections in {:?}...",
+                pre_drain_delay
+            );
+            lifecycle.start_draining();
+            tokio::time::sleep(pre_drain_delay).await;
+            info!("Draining connections...");
+            let _ = draining_tx.send(());
+        });
 
-    let pool = SqlitePoolOptions::new()
-        .connect_with(connect_options)
-        .await?;
+        let drain_timeout = self.drain_timeout;
+        let drain_deadline = async move {
+            match draining_rx.await {
+                Ok(_) => tokio::time::sleep(drain_timeout).await,
+                Err(_) => std::future::pending().await,
+            }
+        };
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+        let result = tokio::select! {
+            result = server => result,
+            _ = drain_deadline => {
+                warn!(
+                    "Drain timeout of {:?} elapsed, aborting remaining requests",
+                    drain_timeout
+                );
+                Ok(())
+            }
+        };
 
-    Ok(pool)
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.abort();
+        }
+        // Events not relayed yet stay in the outbox and are published after the restart
+        if let Some(outbox_relay) = outbox_relay {
+            outbox_relay.abort();
+        }
+        // Deliveries in flight are attempted again, receivers drop duplicates by event id
+        if let Some(webhook_worker) = webhook_worker {
+            webhook_worker.abort();
+        }
+        if let Some(pool) = self.pool {
+            info!("Closing database pool...");
+            pool.close().await;
+        }
+        result?;
+        info!("Shutdown complete");
+
+        Ok(())
+    }
 }
 
 pub async fn init_http_server(
     config: &Config,
-) -> Result<Server<AddrIncoming, IntoMakeService<Router>>, Box<dyn Error>> {
+    log_filter: LogFilter,
+) -> Result<HttpServer, Box<dyn Error>> {
+    let lifecycle = Lifecycle::new();
+
     // init action layer and it's dependencies
-    let todo_store = SqliteTodoStore::new(init_sql_client().await?);
+    let TodoStoreHandle { todo_store, pool } = init_todo_store(config).await?;
+
+    // Webhook deliveries are queued by the outbox relay, next to the configured publisher
+    let webhook_store = match (config.webhooks.enabled, &pool) {
+        (true, Some(DatabasePool::Sqlite(pool))) => Some(Arc::new(WebhookStore::new(pool.clone()))),
+        _ => None,
+    };
+    let webhook_worker = match (&webhook_store, &pool) {
+        (Some(_), Some(DatabasePool::Sqlite(pool))) => {
+            Some(WebhookWorker::new(pool.clone(), &config.webhooks)?)
+        }
+        _ => None,
+    };
+
+    let outbox_relay = match (config.events.enabled, &pool) {
+        (true, Some(DatabasePool::Sqlite(pool))) => {
+            let mut publisher = init_event_publisher(&config.events).await?;
+            if let Some(webhook_store) = &webhook_store {
+                publisher = Arc::new(FanOutPublisher(vec![
+                    publisher,
+                    webhook_store.clone() as EventPublisherArc,
+                ]));
+            }
+            Some(OutboxRelay::new(
+                SqliteOutbox::new(pool.clone()),
+                publisher,
+                config.events.batch_size,
+                config.events.relay_interval,
+            ))
+        }
+        _ => None,
+    };
 
-    let todo_use_case = TodoService::new(Arc::new(todo_store));
+    let readiness = Readiness::new(match &pool {
+        Some(pool) => vec![
+            Arc::new(DatabasePingCheck::new(pool.clone())),
+            Arc::new(MigrationsCheck::new(pool.clone())),
+        ],
+        None => vec![],
+    });
+
+    let metrics = match config.metrics.enabled {
+        true => Some(Arc::new(Metrics::new(pool.clone())?)),
+        false => None,
+    };
+    let todo_store: TodoOutputPortArc = match &metrics {
+        Some(metrics) => Arc::new(MeteredTodoStore::new(todo_store, metrics.clone())),
+        None => todo_store,
+    };
+
+    let todo_use_case =
+        TodoService::new(todo_store).with_feed(TodoFeed::new(config.stream.replay_buffer));
     let shared_todo_use_case = Arc::new(todo_use_case) as TodoInputPortArc;
 
-    let router = Router::new()
+    let mut api = todo_routes().merge(admin_routes(config.auth.enabled, webhook_store));
+
+    // Probes stay open, only the api routes require a bearer token or an API key
+    if config.auth.enabled {
+        let jwt = match config.auth.has_jwt_keys() {
+            true => Some(JwtAuthenticator::new(&config.auth).await?),
+            false => None,
+        };
+        // API keys live in the SQLite database, which the memory store does not connect to
+        let api_keys = match (config.auth.api_keys, &pool) {
+            (false, _) => None,
+            (true, Some(DatabasePool::Sqlite(pool))) => Some(ApiKeyStore::new(pool.clone())),
+            (true, _) => match DatabasePool::connect(&config.database).await? {
+                DatabasePool::Sqlite(pool) => Some(ApiKeyStore::new(pool)),
+                DatabasePool::Postgres(_) => {
+                    return Err("API keys require a SQLite database".into())
+                }
+            },
+        };
+        let authenticator = Arc::new(Authenticator::new(jwt, api_keys));
+        api = api.route_layer(middleware::from_fn_with_state(authenticator, authenticate));
+    } else {
+        warn!("Authentication is disabled, the api is open to anyone and admin endpoints are off");
+        if config.auth.trust_role_headers {
+            api = api.layer(Extension(TrustedRoleHeaders));
+        }
+    }
+
+    let mut router = Router::new()
         .route("/healthz", get(healthz_handler))
         .route("/readyz", get(readyz_handler))
+        .route("/api-docs", get(api_docs_handler))
+        .route("/api-docs/openapi.json", get(openapi_handler))
+        .merge(api)
+        .layer(Extension(shared_todo_use_case))
+        .layer(Extension(lifecycle.clone()))
+        .layer(Extension(readiness))
+        .layer(Extension(log_filter))
+        .layer(Extension(config.stream.clone()));
+
+    let metrics_server = match metrics {
+        Some(metrics) => {
+            router = router.layer(middleware::from_fn_with_state(
+                metrics.clone(),
+                track_requests,
+            ));
+            let addr = SocketAddr::new(config.server.host, config.metrics.port);
+            info!("metrics listening on {}", addr);
+            Some(axum::Server::try_bind(&addr)?.serve(metrics_router(metrics).into_make_service()))
+        }
+        None => None,
+    };
+
+    if config.access_log.enabled {
+        let access_log = Arc::new(AccessLog::new(&config.access_log));
+        router = router.layer(middleware::from_fn_with_state(access_log, log_requests));
+    }
+
+    // Everything handling the request runs within its span, which carries the request id
+    let router = router
+        .layer(middleware::from_fn(trace_requests))
+        .layer(middleware::from_fn(propagate_request_id));
+
+    let addr = SocketAddr::new(config.server.host, config.server.port);
+
+    info!("listening on {}", addr);
+
+    let server = axum::Server::bind(&addr);
+
+    Ok(HttpServer {
+        // The peer address is the client ip of the access log, unless sent by a trusted proxy
+        server: server.serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+        metrics_server,
+        outbox_relay,
+        webhook_worker,
+        pool,
+        lifecycle,
+        pre_drain_delay: config.server.pre_drain_delay,
+        drain_timeout: config.server.drain_timeout,
+    })
+}
+
+// Literal segments take precedence over the `:id` parameter
+fn todo_routes() -> Router {
+    Router::new()
         .route("/api/v1/todos", get(list_todos_handler))
+        .route("/api/v1/todos/events", get(todo_events_handler))
+        .route("/api/v1/ws", get(ws_handler))
         .route("/api/v1/todos/:id", get(get_todo_handler))
         .route("/api/v1/todos", post(create_todo_handler))
+        .route("/api/v1/todos/batch", post(batch_todos_handler))
         .route("/api/v1/todos/:id", put(update_todo_handler))
+        .route("/api/v1/todos/:id", patch(patch_todo_handler))
         .route("/api/v1/todos/:id", delete(delete_todo_handler))
-        .layer(Extension(shared_todo_use_case));
+}
+
+// Admin endpoints trust the caller's roles and tenant, which only authentication vouches for.
+// Without it anyone could claim the admin role with `X-User-Roles` and pick the tenant with
+// `X-Tenant-Id`, so they are not served at all. Webhooks are managed by tenant admins as well.
+fn admin_routes(auth_enabled: bool, webhook_store: Option<Arc<WebhookStore>>) -> Router {
+    if !auth_enabled {
+        return Router::new();
+    }
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    let admin = Router::new()
+        .route("/api/v1/admin/log-filter", get(get_log_filter_handler))
+        .route("/api/v1/admin/log-filter", put(update_log_filter_handler));
+    match webhook_store {
+        Some(webhook_store) => admin.merge(
+            Router::new()
+                .route("/api/v1/webhooks", post(create_webhook_handler))
+                .route("/api/v1/webhooks", get(list_webhooks_handler))
+                .route("/api/v1/webhooks/:id", delete(delete_webhook_handler))
+                .route(
+                    "/api/v1/webhooks/:id/deliveries",
+                    get(list_webhook_deliveries_handler),
+                )
+                .layer(Extension(webhook_store)),
+        ),
+        None => admin,
+    }
+}
 
-    tracing::info!("listening on {}", addr);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use hyper::StatusCode;
+    use tower::ServiceExt;
 
-    let server = axum::Server::bind(&addr);
+    #[tokio::test]
+    async fn todo_routes_should_only_serve_batches_on_their_own_path() {
+        use crate::{todo_store::inmemory::InMemoryTodoStore, use_cases::TodoService};
+
+        let todo_port: TodoInputPortArc =
+            Arc::new(TodoService::new(Arc::new(InMemoryTodoStore::new())));
+        let app = todo_routes().layer(Extension(todo_port));
+        let request = |uri: &str| {
+            Request::post(uri)
+                .header("content-type", "application/json")
+                .header("x-user-id", "alice")
+                .body(Body::from(r#"{"operations": []}"#))
+                .unwrap()
+        };
+
+        for uri in ["/api/v1/todosXYZ", "/api/v1/todos:batch"] {
+            let response = app.clone().oneshot(request(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        let response = app.oneshot(request("/api/v1/todos/batch")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn todo_routes_should_only_trust_role_headers_when_told_to() {
+        use crate::{todo_store::inmemory::InMemoryTodoStore, use_cases::TodoService};
+
+        let todo_port: TodoInputPortArc =
+            Arc::new(TodoService::new(Arc::new(InMemoryTodoStore::new())));
+        let request = |method: &str, user_id: &str, roles: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/api/v1/todos")
+                .header("content-type", "application/json")
+                .header("x-user-id", user_id)
+                .header("x-user-roles", roles)
+                .header("x-tenant-id", "acme")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+        let total_count = |response: axum::response::Response| async move {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            page["total_count"].clone()
+        };
+
+        let app = todo_routes().layer(Extension(todo_port.clone()));
+        let response = app
+            .clone()
+            .oneshot(request(
+                "POST",
+                "alice",
+                "",
+                r#"{"text": "Acme plans", "state": "Opened"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Claiming the admin role does not widen the scope to the tenant
+        let response = app
+            .oneshot(request("GET", "mallory", "admin", ""))
+            .await
+            .unwrap();
+        assert_eq!(total_count(response).await, 0);
+
+        let app = todo_routes()
+            .layer(Extension(TrustedRoleHeaders))
+            .layer(Extension(todo_port));
+        let response = app
+            .oneshot(request("GET", "mallory", "admin", ""))
+            .await
+            .unwrap();
+        assert_eq!(total_count(response).await, 1);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_should_not_be_served_without_authentication() {
+        let pool = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let webhook_store = Arc::new(WebhookStore::new(pool));
+        let request = |method: &str, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .header("x-user-id", "mallory")
+                .header("x-user-roles", "admin")
+                .header("x-tenant-id", "acme")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
 
-    Ok(server.serve(router.into_make_service()))
+        for request in [
+            request(
+                "PUT",
+                "/api/v1/admin/log-filter",
+                r#"{"directives": "trace"}"#,
+            ),
+            request("GET", "/api/v1/webhooks", ""),
+            request(
+                "POST",
+                "/api/v1/webhooks",
+                r#"{"url": "https://attacker.example/hooks"}"#,
+            ),
+        ] {
+            let response = admin_routes(false, Some(webhook_store.clone()))
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
 }
@@ -0,0 +1,359 @@
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use opentelemetry::{propagation::TextMapPropagator, trace::TraceError, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self, TracerProvider},
+    Resource,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+};
+use tracing::{error, field::Empty, info_span, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer,
+    Registry,
+};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::cli::Cli;
+use crate::config::{Config, LogDirectives, LogFormat, TracingConfig};
+use crate::metrics::UNMATCHED_ROUTE;
+use crate::request_id::RequestId;
+
+// Spans are batched and exported in the background, they are flushed when the provider is dropped
+pub fn init_tracer_provider(config: &TracingConfig) -> Result<TracerProvider, TraceError> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build_span_exporter()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .build())
+}
+
+pub struct Telemetry {
+    pub tracer_provider: Option<TracerProvider>,
+    pub log_filter: LogFilter,
+}
+
+// Log lines are written to stdout in the configured format, spans are exported when tracing is
+// enabled. The tracer provider has to be kept alive for as long as spans should be exported.
+pub fn init_telemetry(config: &Config) -> Result<Telemetry, Box<dyn Error>> {
+    let logs = tracing_subscriber::fmt::layer();
+    let logs = match config.logging.format {
+        LogFormat::Json => logs.json().boxed(),
+        LogFormat::Kvp => logs.compact().with_ansi(true).boxed(),
+    };
+    let (log_filter, filter) = LogFilter::new(&config.logging.level);
+
+    let tracer_provider = match config.tracing.enabled {
+        true => Some(init_tracer_provider(&config.tracing)?),
+        false => None,
+    };
+    // Debug and trace events would flood the collector, they only end up in the logs
+    let spans = tracer_provider.as_ref().map(|provider| {
+        use opentelemetry::trace::TracerProvider as _;
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    tracing_subscriber::registry()
+        .with(logs.with_filter(filter))
+        .with(spans.with_filter(LevelFilter::INFO))
+        .try_init()?;
+
+    Ok(Telemetry {
+        tracer_provider,
+        log_filter,
+    })
+}
+
+// Body of the log filter admin endpoint
+#[derive(Serialize, Deserialize, Validate, ToSchema)]
+pub struct LogFilterDirectives {
+    /// `EnvFilter` directives, e.g. `info,rust_axum_service_sample::todo_store=trace`
+    #[validate(length(
+        min = 1,
+        max = 1000,
+        message = "Can not be empty or longer then 1000 characters"
+    ))]
+    #[schema(min_length = 1, max_length = 1000)]
+    pub directives: String,
+}
+
+// Filter of the log lines, which can be swapped while the service is running.
+// Changes are audit logged at warn level, so that they show up with the usual filters.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    current: Arc<Mutex<LogDirectives>>,
+}
+
+impl LogFilter {
+    fn new(directives: &LogDirectives) -> (Self, reload::Layer<EnvFilter, Registry>) {
+        let (filter, handle) = reload::Layer::new(directives.to_filter());
+        let log_filter = Self {
+            handle,
+            current: Arc::new(Mutex::new(directives.clone())),
+        };
+
+        (log_filter, filter)
+    }
+
+    pub fn current(&self) -> LogDirectives {
+        self.current.lock().unwrap().clone()
+    }
+
+    pub fn reload(&self, directives: LogDirectives, changed_by: &str) -> Result<(), reload::Error> {
+        let mut current = self.current.lock().unwrap();
+        warn!(
+            target: "audit",
+            changed_by,
+            previous = %current,
+            directives = %directives,
+            "Changing log filter"
+        );
+
+        self.handle.reload(directives.to_filter())?;
+        *current = directives;
+
+        Ok(())
+    }
+}
+
+// Re-reads the configuration on every SIGHUP and applies its logging level,
+// the remaining settings still require a restart
+#[cfg(unix)]
+pub async fn reload_log_filter_on_sighup(cli: Cli, log_filter: LogFilter) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match Config::load(&cli) {
+            Ok(config) => {
+                if let Err(e) = log_filter.reload(config.logging.level, "SIGHUP") {
+                    error!("Failed to reload log filter: {}", e);
+                }
+            }
+            Err(e) => error!("Ignoring SIGHUP, {}", e),
+        }
+    }
+}
+
+// Middleware continuing the caller's trace (W3C `traceparent` and `tracestate` headers) with a
+// server span per request. Spans are named by the route template, like the request metrics.
+// The request id is recorded on the span, so that it is part of every log line of the request.
+pub async fn trace_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_owned());
+    let method = request.method().clone();
+    let request_id = request.extensions().get::<RequestId>().cloned();
+
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    let span = info_span!(
+        "http_request",
+        otel.name = %format!("{} {}", method, route),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.method = %method,
+        http.route = %route,
+        http.status_code = Empty,
+        request_id = request_id.as_ref().map(tracing::field::display),
+    );
+    span.set_parent(parent);
+
+    let response = next.run(request).instrument(span.clone()).await;
+
+    let status = response.status();
+    span.record("http.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use crate::handlers::create_todo_handler;
+    use crate::todo_store::sqlite::SqliteTodoStore;
+    use crate::use_cases::{TodoInputPortArc, TodoService};
+    use axum::{body::Body, body::Bytes, middleware, routing::post, Extension, Router};
+    use hyper::StatusCode;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::ExportTraceServiceRequest,
+        common::v1::any_value,
+        trace::v1::{span::SpanKind, Span},
+    };
+    use prost::Message;
+    use sqlx::SqlitePool;
+    use std::{net::TcpListener, sync::Arc};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    const STORE_TARGET: &str = "rust_axum_service_sample::todo_store";
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    // Collector stand-in, hands the payloads of the OTLP/HTTP trace endpoint over to the test
+    fn start_receiver() -> (String, mpsc::UnboundedReceiver<Bytes>) {
+        let (payloads, received) = mpsc::unbounded_channel();
+        let receiver = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                payloads.send(body).unwrap();
+                StatusCode::OK
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(receiver.into_make_service()),
+        );
+
+        (endpoint, received)
+    }
+
+    fn find<'a>(spans: &'a [Span], name: &str) -> &'a Span {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("span '{}' was not exported", name))
+    }
+
+    // The batch processor is flushed synchronously, which needs a runtime thread to export
+    #[test]
+    fn log_filter_should_swap_directives_at_runtime() {
+        let (log_filter, filter) = LogFilter::new(&"warn".parse().unwrap());
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::sink)
+                .with_filter(filter),
+        );
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+        let store_debug = || tracing::enabled!(target: STORE_TARGET, tracing::Level::DEBUG);
+        assert!(!store_debug());
+
+        log_filter
+            .reload(
+                "warn,rust_axum_service_sample::todo_store=trace"
+                    .parse()
+                    .unwrap(),
+                "test",
+            )
+            .unwrap();
+
+        assert!(store_debug());
+        assert!(!tracing::enabled!(target: "sqlx", tracing::Level::DEBUG));
+        assert_eq!(
+            log_filter.current().to_string(),
+            "warn,rust_axum_service_sample::todo_store=trace"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_should_be_exported_within_the_callers_trace() {
+        let (endpoint, mut received) = start_receiver();
+        let provider = init_tracer_provider(&TracingConfig {
+            enabled: true,
+            otlp_endpoint: endpoint,
+            service_name: "todo-test".to_owned(),
+        })
+        .unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let todo_port: TodoInputPortArc =
+            Arc::new(TodoService::new(Arc::new(SqliteTodoStore::new(pool))));
+        let app = Router::new()
+            .route("/api/v1/todos", post(create_todo_handler))
+            .layer(Extension(todo_port))
+            .layer(middleware::from_fn(trace_requests));
+
+        let request = Request::post("/api/v1/todos")
+            .header("content-type", "application/json")
+            .header("x-user-id", "alice")
+            .header("traceparent", format!("00-{}-{}-01", TRACE_ID, PARENT_ID))
+            .header("tracestate", "vendor=value")
+            .body(Body::from(r#"{"text":"trace me","state":"Opened"}"#))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        provider.force_flush();
+        let mut payloads = vec![received.recv().await.unwrap()];
+        while let Ok(payload) = received.try_recv() {
+            payloads.push(payload);
+        }
+
+        let requests: Vec<ExportTraceServiceRequest> = payloads
+            .iter()
+            .map(|payload| ExportTraceServiceRequest::decode(payload.as_ref()).unwrap())
+            .collect();
+        let service_name = requests[0].resource_spans[0]
+            .resource
+            .as_ref()
+            .and_then(|resource| {
+                resource
+                    .attributes
+                    .iter()
+                    .find(|attribute| attribute.key == "service.name")
+            })
+            .and_then(|attribute| attribute.value.as_ref()?.value.clone());
+        assert_eq!(
+            service_name,
+            Some(any_value::Value::StringValue("todo-test".to_owned()))
+        );
+
+        let spans: Vec<Span> = requests
+            .into_iter()
+            .flat_map(|request| request.resource_spans)
+            .flat_map(|resource_spans| resource_spans.scope_spans)
+            .flat_map(|scope_spans| scope_spans.spans)
+            .collect();
+        let server = find(&spans, "POST /api/v1/todos");
+        let use_case = find(&spans, "TodoService::create_todo");
+        let query = find(&spans, "INSERT todos");
+
+        assert!(spans
+            .iter()
+            .all(|span| hex::encode(&span.trace_id) == TRACE_ID));
+        assert_eq!(hex::encode(&server.parent_span_id), PARENT_ID);
+        assert_eq!(server.trace_state, "vendor=value");
+        assert_eq!(use_case.parent_span_id, server.span_id);
+        assert_eq!(query.parent_span_id, use_case.span_id);
+        assert_eq!(server.kind, SpanKind::Server as i32);
+        assert_eq!(query.kind, SpanKind::Client as i32);
+    }
+}
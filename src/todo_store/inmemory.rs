@@ -1,71 +1,315 @@
 use crate::error::{Error, Result};
-use crate::model::{Todo, TodoInput};
+use crate::model::{
+    aborted_batch, BatchMode, Identity, Scope, Todo, TodoCursor, TodoInput, TodoOperation,
+    TodoPage, TodoPatch, TodoQuery, TodoSort, TodoState, TodoWritten, UpdatedTodo,
+};
 use crate::use_cases::TodoOutputPort;
 use async_trait::async_trait;
-use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use std::{cmp::Ordering, sync::Mutex};
+
+#[derive(Clone)]
+struct OwnedTodo {
+    tenant_id: Option<String>,
+    todo: Todo,
+}
+
+impl OwnedTodo {
+    fn is_reachable(&self, identity: &Identity, scope: Scope) -> bool {
+        self.tenant_id == identity.tenant_id
+            && (scope == Scope::Tenant || self.todo.owner_id == identity.user_id)
+    }
+}
+
+// Ids are never handed out twice, like the sql stores' sequences, so that an entity tag of a
+// deleted todo can not match a todo created later on
+#[derive(Clone, Default)]
+struct Todos {
+    last_id: u32,
+    entries: Vec<OwnedTodo>,
+}
 
 pub struct InMemoryTodoStore {
-    todo_store: Mutex<Vec<Todo>>,
+    todo_store: Mutex<Todos>,
 }
 
 impl InMemoryTodoStore {
-    #[allow(dead_code)]
-    // Not sure why this is reported as not used while it's used in tests
     pub fn new() -> Self {
         Self {
-            todo_store: Mutex::new(Vec::new()),
+            todo_store: Mutex::new(Todos::default()),
         }
     }
 }
 
+fn matches(query: &TodoQuery, todo: &Todo) -> bool {
+    query
+        .state
+        .as_ref()
+        .is_none_or(|state| &todo.state == state)
+        && query
+            .text_contains
+            .as_ref()
+            .is_none_or(|text| todo.text.contains(text.as_str()))
+}
+
+fn compare(sort: TodoSort, a: &Todo, b: &Todo) -> Ordering {
+    match sort {
+        TodoSort::IdAsc => a.id.cmp(&b.id),
+        TodoSort::IdDesc => b.id.cmp(&a.id),
+        TodoSort::TextAsc => a.text.cmp(&b.text).then(a.id.cmp(&b.id)),
+    }
+}
+
+fn is_after(sort: TodoSort, cursor: &TodoCursor, todo: &Todo) -> bool {
+    match sort {
+        TodoSort::IdAsc => todo.id > cursor.id,
+        TodoSort::IdDesc => todo.id < cursor.id,
+        TodoSort::TextAsc => (todo.text.as_str(), todo.id) > (cursor.text.as_str(), cursor.id),
+    }
+}
+
+// Todos out of scope are indistinguishable from missing ones
+fn owned<'a>(
+    todos: &'a mut [OwnedTodo],
+    identity: &'a Identity,
+    scope: Scope,
+) -> impl Iterator<Item = &'a mut Todo> {
+    todos
+        .iter_mut()
+        .filter(move |entry| entry.is_reachable(identity, scope))
+        .map(|entry| &mut entry.todo)
+}
+
+// Looks up the todo to be modified, checking the expected version when one is given
+fn find_version<'a>(
+    todos: &'a mut [OwnedTodo],
+    identity: &'a Identity,
+    scope: Scope,
+    id: u32,
+    version: Option<u32>,
+) -> Result<&'a mut Todo> {
+    let existing = owned(todos, identity, scope)
+        .find(|todo| todo.id == id)
+        .ok_or(Error::ResourceNotFound {
+            name: "todo".to_owned(),
+            id,
+        })?;
+
+    match version {
+        Some(version) if version != existing.version => Err(Error::stale_version("todo", id)),
+        _ => Ok(existing),
+    }
+}
+
+// Bumps the version and audit fields of a modified todo, `closed_at` follows the state
+fn touch(todo: &mut Todo, state: TodoState, actor: &Identity, now: DateTime<Utc>) {
+    todo.closed_at = match (&state, &todo.state) {
+        (TodoState::Closed, TodoState::Closed) => todo.closed_at,
+        (TodoState::Closed, TodoState::Opened) => Some(now),
+        (TodoState::Opened, _) => None,
+    };
+    todo.state = state;
+    todo.version += 1;
+    todo.updated_at = now;
+    todo.updated_by = Some(actor.user_id.clone());
+}
+
+fn insert(todos: &mut Todos, identity: &Identity, todo: TodoInput) -> Todo {
+    todos.last_id += 1;
+    let now = Utc::now();
+    let new_todo = Todo {
+        id: todos.last_id,
+        closed_at: (todo.state == TodoState::Closed).then_some(now),
+        state: todo.state,
+        text: todo.text,
+        version: 1,
+        created_at: now,
+        updated_at: now,
+        created_by: Some(identity.user_id.clone()),
+        updated_by: Some(identity.user_id.clone()),
+        owner_id: identity.user_id.clone(),
+    };
+
+    todos.entries.push(OwnedTodo {
+        tenant_id: identity.tenant_id.clone(),
+        todo: new_todo.clone(),
+    });
+    new_todo
+}
+
+fn replace(
+    todos: &mut [OwnedTodo],
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    todo: TodoInput,
+    version: Option<u32>,
+) -> Result<UpdatedTodo> {
+    let existing = find_version(todos, identity, scope, id, version)?;
+    let previous_state = existing.state.clone();
+
+    existing.text = todo.text;
+    touch(existing, todo.state, identity, Utc::now());
+
+    Ok(UpdatedTodo {
+        previous_state,
+        todo: existing.clone(),
+    })
+}
+
+// Deleting a missing item is a no-op, same as in the sql stores
+fn remove(
+    todos: &mut Vec<OwnedTodo>,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    version: Option<u32>,
+) -> Result<Option<Todo>> {
+    let deleted = match find_version(todos, identity, scope, id, version) {
+        Ok(todo) => Some(todo.clone()),
+        Err(Error::ResourceNotFound { .. }) => None,
+        Err(error) => return Err(error),
+    };
+
+    todos.retain(|entry| !(entry.todo.id == id && entry.is_reachable(identity, scope)));
+    Ok(deleted)
+}
+
 #[async_trait]
 impl TodoOutputPort for InMemoryTodoStore {
-    async fn list_todos(&self) -> Result<Vec<Todo>> {
-        Ok(self.todo_store.lock().unwrap().to_vec())
+    async fn list_todos(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        query: TodoQuery,
+    ) -> Result<TodoPage> {
+        let mut locked_store = self.todo_store.lock().unwrap();
+        let mut matching: Vec<Todo> = owned(&mut locked_store.entries, identity, scope)
+            .filter(|todo| matches(&query, todo))
+            .map(|todo| todo.clone())
+            .collect();
+        let total_count = matching.len() as u64;
+
+        matching.sort_by(|a, b| compare(query.sort, a, b));
+        let rows = matching
+            .into_iter()
+            .filter(|todo| {
+                query
+                    .after
+                    .as_ref()
+                    .is_none_or(|cursor| is_after(query.sort, cursor, todo))
+            })
+            .take(query.limit as usize + 1)
+            .collect();
+
+        Ok(TodoPage::from_rows(rows, &query, total_count))
     }
 
-    async fn get_todo(&self, id: u32) -> Result<Todo> {
-        let list = self.todo_store.lock().unwrap();
-        let result =
-            list.clone()
-                .into_iter()
-                .find(|todo| todo.id == id)
-                .ok_or(Error::ResourceNotFound {
-                    name: "todo".to_owned(),
-                    id,
-                })?;
-
-        Ok(result)
+    async fn get_todo(&self, identity: &Identity, scope: Scope, id: u32) -> Result<Todo> {
+        let mut locked_store = self.todo_store.lock().unwrap();
+        let result = find_version(&mut locked_store.entries, identity, scope, id, None)?;
+
+        Ok(result.clone())
     }
 
-    async fn create_todo(&self, todo: TodoInput) -> Result<Todo> {
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo> {
         let mut locked_store = self.todo_store.lock().unwrap();
-        let max_id = locked_store.iter().map(|t| t.id).max().unwrap_or(1);
-        let new_todo = Todo {
-            id: max_id + 1,
-            state: todo.state,
-            text: todo.text,
-        };
-
-        locked_store.push(new_todo.clone());
-        Ok(new_todo)
+
+        Ok(insert(&mut locked_store, identity, todo))
     }
 
-    async fn update_todo(&self, id: u32, todo: TodoInput) -> Result<Todo> {
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo> {
         let mut locked_store = self.todo_store.lock().unwrap();
-        let todo_index = locked_store.iter().position(|todo| todo.id == id).unwrap();
 
-        locked_store[todo_index].state = todo.state;
-        locked_store[todo_index].text = todo.text;
+        replace(
+            &mut locked_store.entries,
+            identity,
+            scope,
+            id,
+            todo,
+            version,
+        )
+    }
+
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo> {
+        let mut locked_store = self.todo_store.lock().unwrap();
+        let existing = find_version(&mut locked_store.entries, identity, scope, id, version)?;
+        let previous_state = existing.state.clone();
+
+        if let Some(text) = patch.text {
+            existing.text = text;
+        }
+        let state = patch.state.unwrap_or_else(|| existing.state.clone());
+        touch(existing, state, identity, Utc::now());
+
+        Ok(UpdatedTodo {
+            previous_state,
+            todo: existing.clone(),
+        })
+    }
+
+    async fn delete_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        version: Option<u32>,
+    ) -> Result<Option<Todo>> {
+        let mut locked_store = self.todo_store.lock().unwrap();
 
-        Ok(locked_store[todo_index].clone())
+        remove(&mut locked_store.entries, identity, scope, id, version)
     }
 
-    async fn delete_todo(&self, id: u32) -> Result<()> {
+    // Writes go to a copy of the todos, which replaces them once the batch went through
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        writes: Vec<(Scope, TodoOperation)>,
+        mode: BatchMode,
+    ) -> Result<Vec<Result<TodoWritten>>> {
         let mut locked_store = self.todo_store.lock().unwrap();
-        let todo_index = locked_store.iter().position(|todo| todo.id == id).unwrap();
+        let mut todos = locked_store.clone();
+        let len = writes.len();
+        let mut results = Vec::with_capacity(len);
+
+        for (index, (scope, operation)) in writes.into_iter().enumerate() {
+            let result = match operation {
+                TodoOperation::Create { todo } => {
+                    Ok(TodoWritten::Created(insert(&mut todos, identity, todo)))
+                }
+                TodoOperation::Update { id, todo, version } => {
+                    replace(&mut todos.entries, identity, scope, id, todo, version)
+                        .map(TodoWritten::Updated)
+                }
+                TodoOperation::Delete { id, version } => {
+                    remove(&mut todos.entries, identity, scope, id, version)
+                        .map(TodoWritten::Deleted)
+                }
+            };
+
+            match result {
+                Err(error) if mode == BatchMode::AllOrNothing => {
+                    return Ok(aborted_batch(len, index, error))
+                }
+                result => results.push(result),
+            }
+        }
 
-        locked_store.remove(todo_index);
-        Ok(())
+        *locked_store = todos;
+        Ok(results)
     }
 }
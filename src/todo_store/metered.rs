@@ -0,0 +1,141 @@
+use async_trait::async_trait;
+use std::{future::Future, sync::Arc, time::Instant};
+
+use crate::error::Result;
+use crate::metrics::Metrics;
+use crate::model::{
+    BatchMode, Identity, Scope, Todo, TodoInput, TodoOperation, TodoPage, TodoPatch, TodoQuery,
+    TodoWritten, UpdatedTodo,
+};
+use crate::use_cases::{TodoOutputPort, TodoOutputPortArc};
+
+// Decorator recording the latency of every operation of the wrapped store
+pub struct MeteredTodoStore {
+    inner: TodoOutputPortArc,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredTodoStore {
+    pub fn new(inner: TodoOutputPortArc, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    async fn timed<T>(
+        &self,
+        operation: &str,
+        future: impl Future<Output = Result<T>> + Send,
+    ) -> Result<T> {
+        let started = Instant::now();
+        let result = future.await;
+        self.metrics
+            .observe_store_operation(operation, result.is_ok(), started.elapsed());
+        result
+    }
+}
+
+#[async_trait]
+impl TodoOutputPort for MeteredTodoStore {
+    async fn list_todos(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        query: TodoQuery,
+    ) -> Result<TodoPage> {
+        self.timed("list_todos", self.inner.list_todos(identity, scope, query))
+            .await
+    }
+
+    async fn get_todo(&self, identity: &Identity, scope: Scope, id: u32) -> Result<Todo> {
+        self.timed("get_todo", self.inner.get_todo(identity, scope, id))
+            .await
+    }
+
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo> {
+        self.timed("create_todo", self.inner.create_todo(identity, todo))
+            .await
+    }
+
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo> {
+        self.timed(
+            "update_todo",
+            self.inner.update_todo(identity, scope, id, todo, version),
+        )
+        .await
+    }
+
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo> {
+        self.timed(
+            "patch_todo",
+            self.inner.patch_todo(identity, scope, id, patch, version),
+        )
+        .await
+    }
+
+    async fn delete_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        version: Option<u32>,
+    ) -> Result<Option<Todo>> {
+        self.timed(
+            "delete_todo",
+            self.inner.delete_todo(identity, scope, id, version),
+        )
+        .await
+    }
+
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        writes: Vec<(Scope, TodoOperation)>,
+        mode: BatchMode,
+    ) -> Result<Vec<Result<TodoWritten>>> {
+        self.timed(
+            "batch_todos",
+            self.inner.batch_todos(identity, writes, mode),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::todo_store::inmemory::InMemoryTodoStore;
+
+    #[tokio::test]
+    async fn metered_store_should_record_operation_latencies() {
+        let metrics = Arc::new(Metrics::new(None).unwrap());
+        let store = MeteredTodoStore::new(Arc::new(InMemoryTodoStore::new()), metrics.clone());
+        let alice = Identity::new("alice", None);
+
+        store
+            .list_todos(&alice, Scope::Own, TodoQuery::default())
+            .await
+            .unwrap();
+        assert!(store.get_todo(&alice, Scope::Own, 1).await.is_err());
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered.contains(
+            r#"store_operation_duration_seconds_count{operation="list_todos",outcome="ok"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"store_operation_duration_seconds_count{operation="get_todo",outcome="error"} 1"#
+        ));
+    }
+}
@@ -1,2 +1,77 @@
+use std::{error::Error, sync::Arc};
+use tracing::{info, info_span, Span};
+
+use crate::config::{Config, StoreBackend};
+use crate::database::DatabasePool;
+use crate::model::{Identity, Scope};
+use crate::use_cases::TodoOutputPortArc;
+
+use inmemory::InMemoryTodoStore;
+use postgres::PostgresTodoStore;
+use sqlite::SqliteTodoStore;
+
 pub mod inmemory;
+pub mod metered;
+pub mod postgres;
 pub mod sqlite;
+
+// Owners outside of any tenant are stored with an empty tenant id,
+// which keeps the ownership filter of the sql stores a plain (indexable) equality
+pub(crate) fn tenant_key(identity: &Identity) -> &str {
+    identity.tenant_id.as_deref().unwrap_or_default()
+}
+
+// Owner the todos are restricted to, none when the whole tenant is in scope
+fn owner_filter(identity: &Identity, scope: Scope) -> Option<&str> {
+    match scope {
+        Scope::Own => Some(identity.user_id.as_str()),
+        Scope::Tenant => None,
+    }
+}
+
+// Client span of a single sql statement, named after the OpenTelemetry database conventions.
+// The statement text is left out, the operation and table are enough to find it.
+fn query_span(system: &'static str, operation: &'static str) -> Span {
+    info_span!(
+        "sql_query",
+        otel.name = %format!("{} todos", operation),
+        otel.kind = "client",
+        db.system = system,
+        db.operation = operation,
+        db.sql.table = "todos",
+    )
+}
+
+// The selected store along with the database pool backing it, if any
+pub struct TodoStoreHandle {
+    pub todo_store: TodoOutputPortArc,
+    pub pool: Option<DatabasePool>,
+}
+
+// Store factory, picks the implementation configured by `store.backend`
+pub async fn init_todo_store(config: &Config) -> Result<TodoStoreHandle, Box<dyn Error>> {
+    info!("Using {} todo store", config.store.backend);
+
+    let handle = match config.store.backend {
+        StoreBackend::Memory => TodoStoreHandle {
+            todo_store: Arc::new(InMemoryTodoStore::new()),
+            pool: None,
+        },
+        StoreBackend::Sqlite | StoreBackend::Postgres => {
+            let pool = DatabasePool::connect(&config.database).await?;
+            let todo_store: TodoOutputPortArc = match &pool {
+                DatabasePool::Sqlite(pool) => {
+                    Arc::new(SqliteTodoStore::new(pool.clone()).with_events(config.events.enabled))
+                }
+                DatabasePool::Postgres(pool) => Arc::new(PostgresTodoStore::new(pool.clone())),
+            };
+
+            TodoStoreHandle {
+                todo_store,
+                pool: Some(pool),
+            }
+        }
+    };
+
+    Ok(handle)
+}
@@ -0,0 +1,675 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    postgres::{PgConnection, PgPool, Postgres},
+    Executor, FromRow, QueryBuilder,
+};
+use tracing::Instrument;
+
+use crate::error::Error;
+
+use super::{owner_filter, query_span, tenant_key};
+use crate::model::{
+    aborted_batch, BatchMode, Identity, Scope, Todo, TodoInput, TodoOperation, TodoPage, TodoPatch,
+    TodoQuery, TodoSort, TodoState, TodoWritten, UpdatedTodo,
+};
+use crate::use_cases::TodoOutputPort;
+
+pub struct PostgresTodoStore {
+    pool: PgPool,
+}
+
+impl PostgresTodoStore {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresTodoStore { pool }
+    }
+}
+
+const TODO_COLUMNS: &str =
+    "id, text, state, version, created_at, updated_at, closed_at, created_by, updated_by, owner_id";
+
+// Locks the todo an update targets and reads the state it is in before the update,
+// bound to the same parameters as the update itself
+const PREVIOUS_STATE: &str = "from (select id as previous_id, state as previous_state from todos \
+     where id = $5 and tenant_id = $6 and owner_id = coalesce($8, owner_id) for update) previous";
+
+// Postgres has no unsigned integers, ids are stored as BIGINT
+#[derive(FromRow)]
+struct TodoRow {
+    id: i64,
+    text: String,
+    state: TodoState,
+    version: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    closed_at: Option<DateTime<Utc>>,
+    created_by: Option<String>,
+    updated_by: Option<String>,
+    owner_id: String,
+}
+
+impl TryFrom<TodoRow> for Todo {
+    type Error = Error;
+
+    fn try_from(row: TodoRow) -> Result<Self, Self::Error> {
+        Ok(Todo {
+            id: u32::try_from(row.id).map_err(|_| {
+                Error::Unexpected(anyhow::anyhow!("todo id {} out of range", row.id))
+            })?,
+            text: row.text,
+            state: row.state,
+            version: u32::try_from(row.version).map_err(|_| {
+                Error::Unexpected(anyhow::anyhow!("todo version {} out of range", row.version))
+            })?,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            closed_at: row.closed_at,
+            created_by: row.created_by,
+            updated_by: row.updated_by,
+            owner_id: row.owner_id,
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct UpdatedRow {
+    #[sqlx(flatten)]
+    todo: TodoRow,
+    previous_state: TodoState,
+}
+
+impl TryFrom<UpdatedRow> for UpdatedTodo {
+    type Error = Error;
+
+    fn try_from(row: UpdatedRow) -> Result<Self, Self::Error> {
+        Ok(UpdatedTodo {
+            previous_state: row.previous_state,
+            todo: row.todo.try_into()?,
+        })
+    }
+}
+
+// Text is compared using the "C" collation so the ordering is bytewise like in the other stores
+fn push_filters(
+    builder: &mut QueryBuilder<Postgres>,
+    identity: &Identity,
+    scope: Scope,
+    query: &TodoQuery,
+) {
+    builder
+        .push(" where tenant_id = ")
+        .push_bind(tenant_key(identity).to_owned());
+
+    if let Some(owner) = owner_filter(identity, scope) {
+        builder.push(" and owner_id = ").push_bind(owner.to_owned());
+    }
+
+    if let Some(state) = &query.state {
+        builder.push(" and state = ").push_bind(state.clone());
+    }
+
+    if let Some(text) = &query.text_contains {
+        builder
+            .push(" and strpos(text, ")
+            .push_bind(text.clone())
+            .push(") > 0");
+    }
+}
+
+#[async_trait]
+impl TodoOutputPort for PostgresTodoStore {
+    async fn list_todos(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        query: TodoQuery,
+    ) -> Result<TodoPage, Error> {
+        let mut count = QueryBuilder::new("select count(*) from todos");
+        push_filters(&mut count, identity, scope, &query);
+        let (total_count,) = count
+            .build_query_as::<(i64,)>()
+            .fetch_one(&self.pool)
+            .instrument(query_span("postgresql", "SELECT"))
+            .await?;
+
+        let mut select = QueryBuilder::new(format!("select {} from todos", TODO_COLUMNS));
+        push_filters(&mut select, identity, scope, &query);
+
+        if let Some(cursor) = &query.after {
+            let id = i64::from(cursor.id);
+            match query.sort {
+                TodoSort::IdAsc => select.push(" and id > ").push_bind(id),
+                TodoSort::IdDesc => select.push(" and id < ").push_bind(id),
+                TodoSort::TextAsc => select
+                    .push(" and (text collate \"C\", id) > (")
+                    .push_bind(cursor.text.clone())
+                    .push(", ")
+                    .push_bind(id)
+                    .push(")"),
+            };
+        }
+
+        select.push(match query.sort {
+            TodoSort::IdAsc => " order by id asc",
+            TodoSort::IdDesc => " order by id desc",
+            TodoSort::TextAsc => " order by text collate \"C\" asc, id asc",
+        });
+        select.push(" limit ").push_bind(i64::from(query.limit) + 1);
+
+        let rows = select
+            .build_query_as::<TodoRow>()
+            .fetch_all(&self.pool)
+            .instrument(query_span("postgresql", "SELECT"))
+            .await?
+            .into_iter()
+            .map(Todo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(TodoPage::from_rows(rows, &query, total_count as u64))
+    }
+
+    async fn get_todo(&self, identity: &Identity, scope: Scope, id: u32) -> Result<Todo, Error> {
+        select(&self.pool, identity, scope, id).await
+    }
+
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo, Error> {
+        insert(&mut *self.pool.acquire().await?, identity, todo).await
+    }
+
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo, Error> {
+        let mut conn = self.pool.acquire().await?;
+        replace(&mut conn, identity, scope, id, todo, version).await
+    }
+
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo, Error> {
+        let result = sqlx::query_as::<_, UpdatedRow>(&format!(
+            "update todos set text = coalesce($1, text), state = coalesce($2, state), \
+             version = version + 1, \
+             closed_at = case when coalesce($2, state) != 'Closed' then null when state = 'Closed' then closed_at else $3 end, \
+             updated_at = $3, updated_by = $4 {} \
+             where id = previous_id and version = coalesce($7, version) \
+             returning {}, previous_state",
+            PREVIOUS_STATE, TODO_COLUMNS
+        ))
+        .bind(patch.text)
+        .bind(patch.state)
+        .bind(Utc::now())
+        .bind(&identity.user_id)
+        .bind(i64::from(id))
+        .bind(tenant_key(identity))
+        .bind(version.map(i64::from))
+        .bind(owner_filter(identity, scope))
+        .fetch_optional(&self.pool)
+        .instrument(query_span("postgresql", "UPDATE"))
+        .await?;
+
+        match result {
+            Some(row) => row.try_into(),
+            None => {
+                let mut conn = self.pool.acquire().await?;
+                Err(not_modified(&mut conn, identity, scope, id, version).await)
+            }
+        }
+    }
+
+    async fn delete_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        version: Option<u32>,
+    ) -> Result<Option<Todo>, Error> {
+        let mut conn = self.pool.acquire().await?;
+        remove(&mut conn, identity, scope, id, version).await
+    }
+
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        writes: Vec<(Scope, TodoOperation)>,
+        mode: BatchMode,
+    ) -> Result<Vec<Result<TodoWritten, Error>>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let len = writes.len();
+        let mut results = Vec::with_capacity(len);
+
+        for (index, (scope, operation)) in writes.into_iter().enumerate() {
+            let result = match operation {
+                TodoOperation::Create { todo } => insert(&mut tx, identity, todo)
+                    .await
+                    .map(TodoWritten::Created),
+                TodoOperation::Update { id, todo, version } => {
+                    replace(&mut tx, identity, scope, id, todo, version)
+                        .await
+                        .map(TodoWritten::Updated)
+                }
+                TodoOperation::Delete { id, version } => {
+                    remove(&mut tx, identity, scope, id, version)
+                        .await
+                        .map(TodoWritten::Deleted)
+                }
+            };
+
+            match result {
+                Err(error @ Error::Unexpected(_)) => {
+                    tx.rollback().await?;
+                    return Err(error);
+                }
+                Err(error) if mode == BatchMode::AllOrNothing => {
+                    tx.rollback().await?;
+                    return Ok(aborted_batch(len, index, error));
+                }
+                result => results.push(result),
+            }
+        }
+
+        tx.commit().await?;
+        Ok(results)
+    }
+}
+
+// Statements shared by the single writes and the batches, run on the given connection
+
+async fn select<'e, E>(
+    executor: E,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+) -> Result<Todo, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let result = sqlx::query_as::<_, TodoRow>(&format!(
+        "select {} from todos where id = $1 and tenant_id = $2 and owner_id = coalesce($3, owner_id)",
+        TODO_COLUMNS
+    ))
+    .bind(i64::from(id))
+    .bind(tenant_key(identity))
+    .bind(owner_filter(identity, scope))
+    .fetch_one(executor)
+    .instrument(query_span("postgresql", "SELECT"))
+    .await
+    .map_err(|e| Error::from_with_context(e, "todo".to_owned(), id))?;
+
+    result.try_into()
+}
+
+async fn insert(
+    conn: &mut PgConnection,
+    identity: &Identity,
+    todo: TodoInput,
+) -> Result<Todo, Error> {
+    let result = sqlx::query_as::<_, TodoRow>(&format!(
+        "insert into todos (text, state, created_at, updated_at, closed_at, created_by, updated_by, \
+         tenant_id, owner_id) \
+         values ($1, $2, $3, $3, case when $2 = 'Closed' then $3 end, $4, $4, $5, $4) \
+         returning {}",
+        TODO_COLUMNS
+    ))
+    .bind(todo.text)
+    .bind(todo.state)
+    .bind(Utc::now())
+    .bind(&identity.user_id)
+    .bind(tenant_key(identity))
+    .fetch_one(conn)
+    .instrument(query_span("postgresql", "INSERT"))
+    .await?;
+
+    result.try_into()
+}
+
+// The version is checked in the statement itself so concurrent writers can not both succeed.
+// `closed_at` is stamped on the transition to Closed and cleared when the todo is reopened.
+async fn replace(
+    conn: &mut PgConnection,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    todo: TodoInput,
+    version: Option<u32>,
+) -> Result<UpdatedTodo, Error> {
+    let result = sqlx::query_as::<_, UpdatedRow>(&format!(
+        "update todos set text = $1, state = $2, version = version + 1, \
+         closed_at = case when $2 != 'Closed' then null when state = 'Closed' then closed_at else $3 end, \
+         updated_at = $3, updated_by = $4 {} \
+         where id = previous_id and version = coalesce($7, version) \
+         returning {}, previous_state",
+        PREVIOUS_STATE, TODO_COLUMNS
+    ))
+    .bind(todo.text)
+    .bind(todo.state)
+    .bind(Utc::now())
+    .bind(&identity.user_id)
+    .bind(i64::from(id))
+    .bind(tenant_key(identity))
+    .bind(version.map(i64::from))
+    .bind(owner_filter(identity, scope))
+    .fetch_optional(&mut *conn)
+    .instrument(query_span("postgresql", "UPDATE"))
+    .await?;
+
+    match result {
+        Some(row) => row.try_into(),
+        None => Err(not_modified(conn, identity, scope, id, version).await),
+    }
+}
+
+// Returns the deleted todo, deleting a missing item is a no-op
+async fn remove(
+    conn: &mut PgConnection,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    version: Option<u32>,
+) -> Result<Option<Todo>, Error> {
+    let result = sqlx::query_as::<_, TodoRow>(&format!(
+        "delete from todos where id = $1 and tenant_id = $2 and owner_id = coalesce($3, owner_id) \
+         and version = coalesce($4, version) returning {}",
+        TODO_COLUMNS
+    ))
+    .bind(i64::from(id))
+    .bind(tenant_key(identity))
+    .bind(owner_filter(identity, scope))
+    .bind(version.map(i64::from))
+    .fetch_optional(&mut *conn)
+    .instrument(query_span("postgresql", "DELETE"))
+    .await?;
+
+    match (result, version) {
+        (Some(row), _) => row.try_into().map(Some),
+        (None, Some(_)) => match not_modified(conn, identity, scope, id, version).await {
+            Error::ResourceNotFound { .. } => Ok(None),
+            error => Err(error),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+// A write that matched no row either targeted a missing todo or a stale version
+async fn not_modified(
+    conn: &mut PgConnection,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    version: Option<u32>,
+) -> Error {
+    match (select(conn, identity, scope, id).await, version) {
+        (Ok(_), Some(_)) => Error::stale_version("todo", id),
+        (Ok(_), None) => Error::Unexpected(anyhow::anyhow!("todo {} was not modified", id)),
+        (Err(error), _) => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::POSTGRES_MIGRATOR;
+    use std::{
+        env,
+        net::TcpListener,
+        path::PathBuf,
+        process::{Command, Output},
+    };
+
+    // Throwaway postgres cluster spawned from the locally installed binaries.
+    // Binaries are looked up in PG_BIN_DIR or PATH, tests are skipped when none are found.
+    struct TestPostgres {
+        bin_dir: PathBuf,
+        data_dir: PathBuf,
+        port: u16,
+    }
+
+    impl TestPostgres {
+        fn start() -> Option<Self> {
+            let bin_dir = env::var_os("PG_BIN_DIR").map(PathBuf::from).or_else(|| {
+                env::split_paths(&env::var_os("PATH")?).find(|dir| dir.join("pg_ctl").is_file())
+            });
+            let bin_dir = match bin_dir {
+                Some(bin_dir) => bin_dir,
+                None => {
+                    eprintln!("postgres binaries not found, skipping test");
+                    return None;
+                }
+            };
+
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let data_dir = env::temp_dir().join(format!("todo-pg-{}-{}", std::process::id(), port));
+            let data = data_dir.to_str().unwrap();
+
+            let server = Self {
+                bin_dir,
+                data_dir: data_dir.clone(),
+                port,
+            };
+            server.run("initdb", &["-D", data, "-U", "postgres", "--auth=trust"]);
+            server.run(
+                "pg_ctl",
+                &[
+                    "-D",
+                    data,
+                    "-l",
+                    data_dir.join("server.log").to_str().unwrap(),
+                    "-o",
+                    &format!("-p {} -k {} -c listen_addresses=127.0.0.1", port, data),
+                    "-w",
+                    "start",
+                ],
+            );
+
+            Some(server)
+        }
+
+        fn url(&self) -> String {
+            format!("postgres://postgres@127.0.0.1:{}/postgres", self.port)
+        }
+
+        async fn pool(&self) -> PgPool {
+            let pool = PgPool::connect(&self.url()).await.unwrap();
+            POSTGRES_MIGRATOR.run(&pool).await.unwrap();
+            pool
+        }
+
+        // Postgres refuses to run as root, drop to the postgres user in that case
+        fn run(&self, program: &str, args: &[&str]) -> Output {
+            let program = self.bin_dir.join(program);
+            let output = if is_root() {
+                Command::new("runuser")
+                    .args(["-u", "postgres", "--"])
+                    .arg(&program)
+                    .args(args)
+                    .output()
+            } else {
+                Command::new(&program).args(args).output()
+            }
+            .unwrap();
+
+            assert!(
+                output.status.success(),
+                "{} failed: {}",
+                program.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            output
+        }
+    }
+
+    impl Drop for TestPostgres {
+        fn drop(&mut self) {
+            let data = self.data_dir.to_str().unwrap().to_owned();
+            self.run("pg_ctl", &["-D", &data, "-m", "immediate", "-w", "stop"]);
+            let _ = std::fs::remove_dir_all(&self.data_dir);
+        }
+    }
+
+    fn is_root() -> bool {
+        Command::new("id")
+            .arg("-u")
+            .output()
+            .map(|output| output.stdout.starts_with(b"0\n"))
+            .unwrap_or(false)
+    }
+
+    fn input(text: &str, state: TodoState) -> TodoInput {
+        TodoInput {
+            text: text.to_owned(),
+            state,
+        }
+    }
+
+    #[tokio::test]
+    async fn postgres_store_should_manage_todos() {
+        let server = match TestPostgres::start() {
+            Some(server) => server,
+            None => return,
+        };
+        let store = PostgresTodoStore::new(server.pool().await);
+        let alice = Identity::new("alice", Some("acme"));
+        let bob = Identity::new("bob", Some("acme"));
+
+        let created = store
+            .create_todo(&alice, input("First Test Item", TodoState::Opened))
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .get_todo(&alice, Scope::Own, created.id)
+                .await
+                .unwrap(),
+            created
+        );
+        assert!(matches!(
+            store.get_todo(&bob, Scope::Own, created.id).await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+
+        let updated = store
+            .update_todo(
+                &alice,
+                Scope::Own,
+                created.id,
+                input("Updated Item", TodoState::Closed),
+                Some(created.version),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated.previous_state, TodoState::Opened);
+        let updated = updated.todo;
+        assert_eq!(updated.state, TodoState::Closed);
+        assert!(updated.closed_at.is_some());
+        assert_eq!(updated.updated_by, Some("alice".to_owned()));
+
+        let patched = store
+            .patch_todo(
+                &alice,
+                Scope::Own,
+                created.id,
+                TodoPatch {
+                    text: Some("Patched Item".to_owned()),
+                    state: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(patched.previous_state, TodoState::Closed);
+        let patched = patched.todo;
+        assert_eq!(patched.text, "Patched Item");
+        assert_eq!(patched.state, TodoState::Closed);
+        assert_eq!(patched.version, 3);
+        assert_eq!(patched.closed_at, updated.closed_at);
+        assert!(matches!(
+            store
+                .delete_todo(&alice, Scope::Own, created.id, Some(updated.version))
+                .await,
+            Err(Error::PreconditionFailed(_))
+        ));
+        assert_eq!(
+            store
+                .list_todos(&alice, Scope::Own, TodoQuery::default())
+                .await
+                .unwrap()
+                .items,
+            vec![patched.clone()]
+        );
+        assert_eq!(
+            store
+                .list_todos(&bob, Scope::Own, TodoQuery::default())
+                .await
+                .unwrap()
+                .total_count,
+            0
+        );
+
+        assert_eq!(
+            store
+                .delete_todo(&alice, Scope::Own, created.id, None)
+                .await
+                .unwrap(),
+            Some(patched)
+        );
+        match store.get_todo(&alice, Scope::Own, created.id).await {
+            Err(Error::ResourceNotFound { name, id }) => {
+                assert_eq!(name, "todo".to_owned());
+                assert_eq!(id, created.id);
+            }
+            _ => panic!("The deleted item should not be found."),
+        }
+    }
+
+    #[tokio::test]
+    async fn postgres_store_should_paginate_sorted_by_text() {
+        let server = match TestPostgres::start() {
+            Some(server) => server,
+            None => return,
+        };
+        let store = PostgresTodoStore::new(server.pool().await);
+        let alice = Identity::new("alice", None);
+
+        for text in ["b item", "B item", "a item", "a item", "skipped"] {
+            store
+                .create_todo(&alice, input(text, TodoState::Opened))
+                .await
+                .unwrap();
+        }
+
+        let mut query = TodoQuery {
+            limit: 2,
+            text_contains: Some("item".to_owned()),
+            sort: TodoSort::TextAsc,
+            ..TodoQuery::default()
+        };
+        let mut pages = vec![];
+        loop {
+            let page = store
+                .list_todos(&alice, Scope::Own, query.clone())
+                .await
+                .unwrap();
+            assert_eq!(page.total_count, 4);
+            pages.push(page.items.iter().map(|t| t.id).collect::<Vec<_>>());
+            match page.next_cursor {
+                Some(cursor) => query.after = Some(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, vec![vec![2, 3], vec![4, 1]]);
+    }
+}
@@ -1,75 +1,698 @@
 use async_trait::async_trait;
-use sqlx::sqlite::SqlitePool;
+use chrono::Utc;
+use sqlx::{
+    sqlite::{Sqlite, SqliteConnection, SqlitePool},
+    Executor, QueryBuilder, Transaction,
+};
+use tracing::Instrument;
 
 use crate::error::Error;
+use crate::events::{outbox::SqliteOutbox, TodoEvent};
 
-use crate::model::{Todo, TodoInput};
+use super::{owner_filter, query_span, tenant_key};
+use crate::model::{
+    aborted_batch, BatchMode, Identity, Scope, Todo, TodoInput, TodoOperation, TodoPage, TodoPatch,
+    TodoQuery, TodoSort, TodoWritten, UpdatedTodo,
+};
 use crate::use_cases::TodoOutputPort;
 
 pub struct SqliteTodoStore {
     pool: SqlitePool,
+    events: bool,
 }
 
 impl SqliteTodoStore {
     pub fn new(pool: SqlitePool) -> Self {
-        SqliteTodoStore { pool }
+        SqliteTodoStore {
+            pool,
+            events: false,
+        }
+    }
+
+    // Records a domain event in the outbox with every change
+    pub fn with_events(self, events: bool) -> Self {
+        SqliteTodoStore { events, ..self }
+    }
+}
+
+const TODO_COLUMNS: &str =
+    "id, text, state, version, created_at, updated_at, closed_at, created_by, updated_by, owner_id";
+
+fn push_filters(
+    builder: &mut QueryBuilder<Sqlite>,
+    identity: &Identity,
+    scope: Scope,
+    query: &TodoQuery,
+) {
+    builder
+        .push(" where tenant_id = ")
+        .push_bind(tenant_key(identity).to_owned());
+
+    if let Some(owner) = owner_filter(identity, scope) {
+        builder.push(" and owner_id = ").push_bind(owner.to_owned());
+    }
+
+    if let Some(state) = &query.state {
+        builder.push(" and state = ").push_bind(state.clone());
+    }
+
+    if let Some(text) = &query.text_contains {
+        builder
+            .push(" and instr(text, ")
+            .push_bind(text.clone())
+            .push(") > 0");
     }
 }
 
 #[async_trait]
 impl TodoOutputPort for SqliteTodoStore {
-    async fn list_todos(&self) -> Result<Vec<Todo>, Error> {
-        let result = sqlx::query_as::<_, Todo>("select id, text, state from todos")
+    async fn list_todos(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        query: TodoQuery,
+    ) -> Result<TodoPage, Error> {
+        let mut count = QueryBuilder::new("select count(*) from todos");
+        push_filters(&mut count, identity, scope, &query);
+        let (total_count,) = count
+            .build_query_as::<(i64,)>()
+            .fetch_one(&self.pool)
+            .instrument(query_span("sqlite", "SELECT"))
+            .await?;
+
+        let mut select = QueryBuilder::new(format!("select {} from todos", TODO_COLUMNS));
+        push_filters(&mut select, identity, scope, &query);
+
+        if let Some(cursor) = &query.after {
+            match query.sort {
+                TodoSort::IdAsc => select.push(" and id > ").push_bind(cursor.id),
+                TodoSort::IdDesc => select.push(" and id < ").push_bind(cursor.id),
+                TodoSort::TextAsc => select
+                    .push(" and (text > ")
+                    .push_bind(cursor.text.clone())
+                    .push(" or (text = ")
+                    .push_bind(cursor.text.clone())
+                    .push(" and id > ")
+                    .push_bind(cursor.id)
+                    .push("))"),
+            };
+        }
+
+        select.push(match query.sort {
+            TodoSort::IdAsc => " order by id asc",
+            TodoSort::IdDesc => " order by id desc",
+            TodoSort::TextAsc => " order by text asc, id asc",
+        });
+        select.push(" limit ").push_bind(query.limit + 1);
+
+        let rows = select
+            .build_query_as::<Todo>()
             .fetch_all(&self.pool)
+            .instrument(query_span("sqlite", "SELECT"))
             .await?;
 
-        Ok(result)
+        Ok(TodoPage::from_rows(rows, &query, total_count as u64))
     }
 
-    async fn get_todo(&self, id: u32) -> Result<Todo, Error> {
-        let result = sqlx::query_as::<_, Todo>("select id, text, state from todos where id = ?")
-            .bind(id)
-            .fetch_one(&self.pool)
-            .await
-            .map_err(|e| Error::from_with_context(e, "todo".to_owned(), id))?;
+    async fn get_todo(&self, identity: &Identity, scope: Scope, id: u32) -> Result<Todo, Error> {
+        select(&self.pool, identity, scope, id).await
+    }
+
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo, Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = insert(&mut tx, identity, todo).await?;
+
+        self.record(&mut tx, identity, TodoEvent::Created(result.clone()))
+            .await?;
+        tx.commit().await?;
 
         Ok(result)
     }
 
-    async fn create_todo(&self, todo: TodoInput) -> Result<Todo, Error> {
-        let result = sqlx::query_as::<_, Todo>(
-            "insert into todos (text, state) values (? ,?) returning id, text, state",
-        )
-        .bind(todo.text)
-        .bind(todo.state)
-        .fetch_one(&self.pool)
-        .await?;
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo, Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = replace(&mut tx, identity, scope, id, todo, version).await;
 
-        Ok(result)
+        self.finish_update(tx, identity, result).await
     }
 
-    async fn update_todo(&self, id: u32, todo: TodoInput) -> Result<Todo, Error> {
-        let result = sqlx::query_as::<_, Todo>(
-            "update todos set text = ?, state = ? where id = ? returning id, text, state",
-        )
-        .bind(todo.text)
-        .bind(todo.state)
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo, Error> {
+        let mut tx = self.pool.begin().await?;
+        let previous_state = match select(&mut tx, identity, scope, id).await {
+            Ok(previous) => previous.state,
+            Err(error) => return self.finish_update(tx, identity, Err(error)).await,
+        };
+        let result = sqlx::query_as::<_, Todo>(&format!(
+            "update todos set text = coalesce(?1, text), state = coalesce(?2, state), \
+             version = version + 1, \
+             closed_at = case when coalesce(?2, state) != 'Closed' then null when state = 'Closed' then closed_at else ?3 end, \
+             updated_at = ?3, updated_by = ?4 \
+             where id = ?5 and tenant_id = ?6 and owner_id = coalesce(?8, owner_id) \
+             and version = coalesce(?7, version) \
+             returning {}",
+            TODO_COLUMNS
+        ))
+        .bind(patch.text)
+        .bind(patch.state)
+        .bind(Utc::now())
+        .bind(&identity.user_id)
         .bind(id)
-        .fetch_one(&self.pool)
-        // TODO: find a better way how to do this
-        // We want to pass the context to our error but ideally without boilerplate
-        .await
-        .map_err(|e| Error::from_with_context(e, "todo".to_owned(), id))?;
+        .bind(tenant_key(identity))
+        .bind(version)
+        .bind(owner_filter(identity, scope))
+        .fetch_optional(&mut tx)
+        .instrument(query_span("sqlite", "UPDATE"))
+        .await;
 
-        Ok(result)
+        let result = match result {
+            Ok(Some(todo)) => Ok(UpdatedTodo {
+                previous_state,
+                todo,
+            }),
+            Ok(None) => Err(not_modified(&mut tx, identity, scope, id, version).await),
+            Err(e) => Err(e.into()),
+        };
+        self.finish_update(tx, identity, result).await
     }
 
-    async fn delete_todo(&self, id: u32) -> Result<(), Error> {
-        sqlx::query("delete from todos where id = ?")
-            .bind(id)
-            .execute(&self.pool)
-            .await?;
+    async fn delete_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        version: Option<u32>,
+    ) -> Result<Option<Todo>, Error> {
+        let mut tx = self.pool.begin().await?;
+
+        match remove(&mut tx, identity, scope, id, version).await {
+            Ok(Some(todo)) => {
+                self.record(&mut tx, identity, TodoEvent::Deleted { id })
+                    .await?;
+                tx.commit().await?;
+                Ok(Some(todo))
+            }
+            Ok(None) => {
+                tx.rollback().await?;
+                Ok(None)
+            }
+            Err(error) => {
+                tx.rollback().await?;
+                Err(error)
+            }
+        }
+    }
+
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        writes: Vec<(Scope, TodoOperation)>,
+        mode: BatchMode,
+    ) -> Result<Vec<Result<TodoWritten, Error>>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let len = writes.len();
+        let mut results = Vec::with_capacity(len);
 
+        for (index, (scope, operation)) in writes.into_iter().enumerate() {
+            let result = match operation {
+                TodoOperation::Create { todo } => insert(&mut tx, identity, todo)
+                    .await
+                    .map(TodoWritten::Created),
+                TodoOperation::Update { id, todo, version } => {
+                    replace(&mut tx, identity, scope, id, todo, version)
+                        .await
+                        .map(TodoWritten::Updated)
+                }
+                TodoOperation::Delete { id, version } => {
+                    remove(&mut tx, identity, scope, id, version)
+                        .await
+                        .map(TodoWritten::Deleted)
+                }
+            };
+
+            match result {
+                Ok(written) => {
+                    if let Some(event) = TodoEvent::written(&written) {
+                        self.record(&mut tx, identity, event).await?;
+                    }
+                    results.push(Ok(written));
+                }
+                Err(error @ Error::Unexpected(_)) => {
+                    tx.rollback().await?;
+                    return Err(error);
+                }
+                Err(error) if mode == BatchMode::AllOrNothing => {
+                    tx.rollback().await?;
+                    return Ok(aborted_batch(len, index, error));
+                }
+                Err(error) => results.push(Err(error)),
+            }
+        }
+
+        tx.commit().await?;
+        Ok(results)
+    }
+}
+
+impl SqliteTodoStore {
+    async fn record(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        identity: &Identity,
+        event: TodoEvent,
+    ) -> Result<(), Error> {
+        if self.events {
+            SqliteOutbox::record(tx, tenant_key(identity), &event).await?;
+        }
         Ok(())
     }
+
+    // Commits an update along with its event. The transaction is rolled back when the update
+    // failed, it would otherwise hold the only connection of an in-memory database.
+    async fn finish_update(
+        &self,
+        mut tx: Transaction<'_, Sqlite>,
+        identity: &Identity,
+        result: Result<UpdatedTodo, Error>,
+    ) -> Result<UpdatedTodo, Error> {
+        match result {
+            Ok(updated) => {
+                self.record(&mut tx, identity, TodoEvent::changed(updated.clone()))
+                    .await?;
+                tx.commit().await?;
+                Ok(updated)
+            }
+            Err(error) => {
+                tx.rollback().await?;
+                Err(error)
+            }
+        }
+    }
+}
+
+// Statements shared by the single writes and the batches, run within the caller's transaction
+
+async fn select<'e, E>(
+    executor: E,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+) -> Result<Todo, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_as::<_, Todo>(&format!(
+        "select {} from todos where id = ? and tenant_id = ? and owner_id = coalesce(?, owner_id)",
+        TODO_COLUMNS
+    ))
+    .bind(id)
+    .bind(tenant_key(identity))
+    .bind(owner_filter(identity, scope))
+    .fetch_one(executor)
+    .instrument(query_span("sqlite", "SELECT"))
+    .await
+    .map_err(|e| Error::from_with_context(e, "todo".to_owned(), id))
+}
+
+async fn insert(
+    conn: &mut SqliteConnection,
+    identity: &Identity,
+    todo: TodoInput,
+) -> Result<Todo, Error> {
+    let result = sqlx::query_as::<_, Todo>(&format!(
+        "insert into todos (text, state, created_at, updated_at, closed_at, created_by, updated_by, \
+         tenant_id, owner_id) \
+         values (?1, ?2, ?3, ?3, case when ?2 = 'Closed' then ?3 end, ?4, ?4, ?5, ?4) \
+         returning {}",
+        TODO_COLUMNS
+    ))
+    .bind(todo.text)
+    .bind(todo.state)
+    .bind(Utc::now())
+    .bind(&identity.user_id)
+    .bind(tenant_key(identity))
+    .fetch_one(conn)
+    .instrument(query_span("sqlite", "INSERT"))
+    .await?;
+
+    Ok(result)
+}
+
+// The version is checked in the statement itself so concurrent writers can not both succeed.
+// `closed_at` is stamped on the transition to Closed and cleared when the todo is reopened.
+// The previous state is read within the same transaction, which tells closing from editing.
+async fn replace(
+    conn: &mut SqliteConnection,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    todo: TodoInput,
+    version: Option<u32>,
+) -> Result<UpdatedTodo, Error> {
+    let previous_state = select(&mut *conn, identity, scope, id).await?.state;
+    let result = sqlx::query_as::<_, Todo>(&format!(
+        "update todos set text = ?1, state = ?2, version = version + 1, \
+         closed_at = case when ?2 != 'Closed' then null when state = 'Closed' then closed_at else ?3 end, \
+         updated_at = ?3, updated_by = ?4 \
+         where id = ?5 and tenant_id = ?6 and owner_id = coalesce(?8, owner_id) \
+         and version = coalesce(?7, version) \
+         returning {}",
+        TODO_COLUMNS
+    ))
+    .bind(todo.text)
+    .bind(todo.state)
+    .bind(Utc::now())
+    .bind(&identity.user_id)
+    .bind(id)
+    .bind(tenant_key(identity))
+    .bind(version)
+    .bind(owner_filter(identity, scope))
+    .fetch_optional(&mut *conn)
+    .instrument(query_span("sqlite", "UPDATE"))
+    .await?;
+
+    match result {
+        Some(todo) => Ok(UpdatedTodo {
+            previous_state,
+            todo,
+        }),
+        None => Err(not_modified(conn, identity, scope, id, version).await),
+    }
+}
+
+// Returns the deleted todo, deleting a missing item is a no-op
+async fn remove(
+    conn: &mut SqliteConnection,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    version: Option<u32>,
+) -> Result<Option<Todo>, Error> {
+    let result = sqlx::query_as::<_, Todo>(&format!(
+        "delete from todos where id = ? and tenant_id = ? and owner_id = coalesce(?, owner_id) \
+         and version = coalesce(?, version) returning {}",
+        TODO_COLUMNS
+    ))
+    .bind(id)
+    .bind(tenant_key(identity))
+    .bind(owner_filter(identity, scope))
+    .bind(version)
+    .fetch_optional(&mut *conn)
+    .instrument(query_span("sqlite", "DELETE"))
+    .await?;
+
+    match (result, version) {
+        (Some(todo), _) => Ok(Some(todo)),
+        (None, Some(_)) => match not_modified(conn, identity, scope, id, version).await {
+            Error::ResourceNotFound { .. } => Ok(None),
+            error => Err(error),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+// A write that matched no row either targeted a missing todo or a stale version
+async fn not_modified(
+    conn: &mut SqliteConnection,
+    identity: &Identity,
+    scope: Scope,
+    id: u32,
+    version: Option<u32>,
+) -> Error {
+    match (select(conn, identity, scope, id).await, version) {
+        (Ok(_), Some(_)) => Error::stale_version("todo", id),
+        (Ok(_), None) => Error::Unexpected(anyhow::anyhow!("todo {} was not modified", id)),
+        (Err(error), _) => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use crate::model::TodoState;
+
+    fn input(text: &str) -> TodoInput {
+        TodoInput {
+            text: text.to_owned(),
+            state: TodoState::Opened,
+        }
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_should_paginate_sorted_by_text() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = SqliteTodoStore::new(pool);
+        let alice = Identity::new("alice", None);
+
+        for text in ["b item", "B item", "a item", "a item", "skipped"] {
+            store.create_todo(&alice, input(text)).await.unwrap();
+        }
+        // Same owner in a tenant is a different identity
+        store
+            .create_todo(&Identity::new("alice", Some("acme")), input("c item"))
+            .await
+            .unwrap();
+
+        let mut query = TodoQuery {
+            limit: 2,
+            text_contains: Some("item".to_owned()),
+            sort: TodoSort::TextAsc,
+            ..TodoQuery::default()
+        };
+        let mut pages = vec![];
+        loop {
+            let page = store
+                .list_todos(&alice, Scope::Own, query.clone())
+                .await
+                .unwrap();
+            assert_eq!(page.total_count, 4);
+            pages.push(page.items.iter().map(|t| t.id).collect::<Vec<_>>());
+            match page.next_cursor {
+                Some(cursor) => query.after = Some(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, vec![vec![2, 3], vec![4, 1]]);
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_should_check_version_and_owner_on_write() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = SqliteTodoStore::new(pool);
+        let alice = Identity::new("alice", None);
+        let bob = Identity::new("bob", None);
+
+        let created = store
+            .create_todo(&alice, input("First Test Item"))
+            .await
+            .unwrap();
+        assert_eq!(created.version, 1);
+        assert_eq!(created.created_by, Some("alice".to_owned()));
+
+        let patch = TodoPatch {
+            state: Some(TodoState::Closed),
+            ..TodoPatch::default()
+        };
+        assert!(matches!(
+            store
+                .patch_todo(&bob, Scope::Own, created.id, patch.clone(), None)
+                .await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+        // The tenant scope reaches todos of other owners, but never another tenant
+        assert_eq!(
+            store
+                .get_todo(&bob, Scope::Tenant, created.id)
+                .await
+                .unwrap(),
+            created
+        );
+        assert!(matches!(
+            store
+                .get_todo(
+                    &Identity::new("bob", Some("acme")),
+                    Scope::Tenant,
+                    created.id
+                )
+                .await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+
+        let patched = store
+            .patch_todo(&alice, Scope::Own, created.id, patch.clone(), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(patched.previous_state, TodoState::Opened);
+        let patched = patched.todo;
+        assert_eq!(patched.version, 2);
+        assert!(patched
+            .closed_at
+            .is_some_and(|closed_at| closed_at >= created.updated_at));
+        assert_eq!(patched.created_at, created.created_at);
+
+        assert!(matches!(
+            store
+                .patch_todo(&alice, Scope::Own, created.id, patch, Some(1))
+                .await,
+            Err(Error::PreconditionFailed(_))
+        ));
+        assert!(matches!(
+            store
+                .delete_todo(&alice, Scope::Own, created.id, Some(1))
+                .await,
+            Err(Error::PreconditionFailed(_))
+        ));
+        assert_eq!(
+            store
+                .delete_todo(&bob, Scope::Own, created.id, Some(2))
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            store
+                .delete_todo(&alice, Scope::Own, created.id, Some(2))
+                .await
+                .unwrap(),
+            Some(patched)
+        );
+        assert!(matches!(
+            store.get_todo(&alice, Scope::Own, created.id).await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_should_record_events_of_committed_changes_only() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = SqliteTodoStore::new(pool.clone()).with_events(true);
+        let alice = Identity::new("alice", Some("acme"));
+
+        let created = store.create_todo(&alice, input("Item")).await.unwrap();
+        let closed = TodoInput {
+            text: "Item".to_owned(),
+            state: TodoState::Closed,
+        };
+        store
+            .update_todo(&alice, Scope::Own, created.id, closed.clone(), None)
+            .await
+            .unwrap();
+        store
+            .update_todo(&alice, Scope::Own, created.id, closed.clone(), None)
+            .await
+            .unwrap();
+        // Neither a stale write nor a no-op delete is an event
+        assert!(store
+            .update_todo(&alice, Scope::Own, created.id, closed, Some(1))
+            .await
+            .is_err());
+        store
+            .delete_todo(&alice, Scope::Own, 42, None)
+            .await
+            .unwrap();
+        store
+            .delete_todo(&alice, Scope::Own, created.id, None)
+            .await
+            .unwrap();
+
+        let events: Vec<(String, u32, String)> =
+            sqlx::query_as("select event_type, todo_id, tenant_id from outbox order by id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        let types: Vec<&str> = events.iter().map(|(t, _, _)| t.as_str()).collect();
+        assert_eq!(
+            types,
+            vec!["TodoCreated", "TodoClosed", "TodoUpdated", "TodoDeleted"]
+        );
+        assert!(events
+            .iter()
+            .all(|(_, id, tenant)| *id == created.id && tenant == "acme"));
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_should_roll_back_failed_all_or_nothing_batches() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = SqliteTodoStore::new(pool.clone()).with_events(true);
+        let alice = Identity::new("alice", None);
+        let created = store.create_todo(&alice, input("Item")).await.unwrap();
+
+        let writes = || {
+            vec![
+                (Scope::Own, TodoOperation::Create { todo: input("New") }),
+                (
+                    Scope::Own,
+                    TodoOperation::Delete {
+                        id: created.id,
+                        version: Some(2),
+                    },
+                ),
+                (
+                    Scope::Own,
+                    TodoOperation::Update {
+                        id: created.id,
+                        todo: input("Renamed"),
+                        version: Some(1),
+                    },
+                ),
+            ]
+        };
+        let statuses = |results: &[Result<TodoWritten, Error>]| -> Vec<u16> {
+            results
+                .iter()
+                .map(|result| match result {
+                    Ok(_) => 200,
+                    Err(error) => error.status_code().as_u16(),
+                })
+                .collect()
+        };
+
+        let results = store
+            .batch_todos(&alice, writes(), BatchMode::AllOrNothing)
+            .await
+            .unwrap();
+        assert_eq!(statuses(&results), vec![424, 412, 424]);
+        let page = store
+            .list_todos(&alice, Scope::Own, TodoQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(page.items, vec![created.clone()]);
+
+        let results = store
+            .batch_todos(&alice, writes(), BatchMode::BestEffort)
+            .await
+            .unwrap();
+        assert_eq!(statuses(&results), vec![200, 412, 200]);
+        let page = store
+            .list_todos(&alice, Scope::Own, TodoQuery::default())
+            .await
+            .unwrap();
+        let texts: Vec<&str> = page.items.iter().map(|todo| todo.text.as_str()).collect();
+        assert_eq!(texts, vec!["Renamed", "New"]);
+
+        let (events,): (i64,) = sqlx::query_as("select count(*) from outbox")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(events, 3);
+    }
 }
@@ -1,8 +1,18 @@
 use async_trait::async_trait;
 use std::sync::Arc;
+use tracing::instrument;
+use validator::Validate;
 
-use crate::error::Result;
-use crate::model::{Todo, TodoInput};
+use crate::error::{Error, Result};
+use crate::events::{
+    feed::{TodoFeed, TodoSubscription},
+    TodoEvent,
+};
+use crate::model::{
+    aborted_batch, BatchMode, Identity, Scope, Todo, TodoInput, TodoOperation, TodoPage, TodoPatch,
+    TodoQuery, TodoState, TodoWritten, UpdatedTodo,
+};
+use crate::policy::{Operation, Policy};
 
 // This is rust specific thing. We need to be able to send the stuff across threads
 // TODO: figure out if there's an alternative way how to strucutre the app
@@ -10,66 +20,329 @@ pub type TodoInputPortArc = Arc<dyn TodoInputPort + Send + Sync>;
 pub type TodoOutputPortArc = Arc<dyn TodoOutputPort + Send + Sync>;
 
 // This is the user case (input port defines invokable logic)
+// Every operation is scoped to the calling identity
 #[async_trait]
 pub trait TodoInputPort {
-    async fn list_todos(&self) -> Result<Vec<Todo>>;
-    async fn get_todo(&self, id: u32) -> Result<Todo>;
-    async fn create_todo(&self, todo: TodoInput) -> Result<Todo>;
-    async fn update_todo(&self, id: u32, todo: TodoInput) -> Result<Todo>;
-    async fn delete_todo(&self, id: u32) -> Result<()>;
+    async fn list_todos(&self, identity: &Identity, query: TodoQuery) -> Result<TodoPage>;
+    async fn get_todo(&self, identity: &Identity, id: u32) -> Result<Todo>;
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo>;
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<Todo>;
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<Todo>;
+    async fn delete_todo(&self, identity: &Identity, id: u32, version: Option<u32>) -> Result<()>;
+    // Results are in the order of the operations, the batch only fails as a whole on
+    // unexpected errors
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        mode: BatchMode,
+        operations: Vec<TodoOperation>,
+    ) -> Result<Vec<Result<TodoWritten>>>;
+    // Live changes of the todos the caller may list, resumed after `last_event_id` if given
+    async fn watch_todos(
+        &self,
+        identity: &Identity,
+        state: Option<TodoState>,
+        last_event_id: Option<&str>,
+    ) -> Result<TodoSubscription>;
 }
 
 // This is sotre (output port defines dependency of the user case)
+// Stores only ever read and write the todos of the given identity within the granted scope
 #[async_trait]
 pub trait TodoOutputPort {
-    async fn list_todos(&self) -> Result<Vec<Todo>>;
-    async fn get_todo(&self, id: u32) -> Result<Todo>;
-    async fn create_todo(&self, todo: TodoInput) -> Result<Todo>;
-    async fn update_todo(&self, id: u32, todo: TodoInput) -> Result<Todo>;
-    async fn delete_todo(&self, id: u32) -> Result<()>;
+    async fn list_todos(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        query: TodoQuery,
+    ) -> Result<TodoPage>;
+    async fn get_todo(&self, identity: &Identity, scope: Scope, id: u32) -> Result<Todo>;
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo>;
+    // Writes report what they changed, which the events are derived from. Updates report the
+    // state the todo was in before, deletes the todo as it was deleted, none if it was missing.
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo>;
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<UpdatedTodo>;
+    async fn delete_todo(
+        &self,
+        identity: &Identity,
+        scope: Scope,
+        id: u32,
+        version: Option<u32>,
+    ) -> Result<Option<Todo>>;
+    // Applies the writes in order within a single transaction. All-or-nothing batches stop at
+    // the first failing write and roll back, best-effort batches keep the writes that succeeded.
+    // Failing writes change nothing, unexpected errors fail and roll back the whole batch.
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        writes: Vec<(Scope, TodoOperation)>,
+        mode: BatchMode,
+    ) -> Result<Vec<Result<TodoWritten>>>;
 }
 
+// Replay buffer of the change feed, unless configured otherwise
+const DEFAULT_REPLAY_BUFFER: usize = 1000;
+
 pub struct TodoService {
     todo_store: TodoOutputPortArc,
+    policy: Policy,
+    feed: TodoFeed,
 }
 
 impl TodoService {
     pub fn new(todo_store: TodoOutputPortArc) -> Self {
-        Self { todo_store }
+        Self::with_policy(todo_store, Policy::default())
+    }
+
+    pub fn with_policy(todo_store: TodoOutputPortArc, policy: Policy) -> Self {
+        Self {
+            todo_store,
+            policy,
+            feed: TodoFeed::new(DEFAULT_REPLAY_BUFFER),
+        }
+    }
+
+    pub fn with_feed(self, feed: TodoFeed) -> Self {
+        Self { feed, ..self }
+    }
+
+    // Validates and authorizes an operation of a batch like the equivalent single request
+    fn check(
+        &self,
+        identity: &Identity,
+        operation: TodoOperation,
+    ) -> Result<(Scope, TodoOperation)> {
+        let kind = match &operation {
+            TodoOperation::Create { todo } => {
+                todo.validate()?;
+                Operation::Create
+            }
+            TodoOperation::Update { todo, .. } => {
+                todo.validate()?;
+                Operation::Update
+            }
+            TodoOperation::Delete { .. } => Operation::Delete,
+        };
+
+        let scope = self.policy.authorize(identity, kind)?;
+        Ok((scope, operation))
     }
 }
 
 // There's not much logic needed as the sample demostrated a CRUD app
 // This would usually hold the application specific (use case logic)
+// Every operation is authorized first, the policy decides which todos the store may touch.
+// Each call gets a span of its own, nested between the request and the sql spans.
 #[async_trait]
 impl TodoInputPort for TodoService {
-    async fn list_todos(&self) -> Result<Vec<Todo>> {
-        Ok(self.todo_store.list_todos().await?)
+    #[instrument(name = "TodoService::list_todos", skip_all, fields(user.id = %identity.user_id))]
+    async fn list_todos(&self, identity: &Identity, query: TodoQuery) -> Result<TodoPage> {
+        let scope = self.policy.authorize(identity, Operation::List)?;
+        Ok(self.todo_store.list_todos(identity, scope, query).await?)
+    }
+
+    #[instrument(
+        name = "TodoService::get_todo",
+        skip_all,
+        fields(user.id = %identity.user_id, todo.id = id)
+    )]
+    async fn get_todo(&self, identity: &Identity, id: u32) -> Result<Todo> {
+        let scope = self.policy.authorize(identity, Operation::Get)?;
+        Ok(self.todo_store.get_todo(identity, scope, id).await?)
+    }
+
+    #[instrument(name = "TodoService::create_todo", skip_all, fields(user.id = %identity.user_id))]
+    async fn create_todo(&self, identity: &Identity, todo: TodoInput) -> Result<Todo> {
+        self.policy.authorize(identity, Operation::Create)?;
+        let todo = self.todo_store.create_todo(identity, todo).await?;
+
+        self.feed
+            .publish(identity, &todo, TodoEvent::Created(todo.clone()));
+        Ok(todo)
+    }
+
+    #[instrument(
+        name = "TodoService::update_todo",
+        skip_all,
+        fields(user.id = %identity.user_id, todo.id = id)
+    )]
+    async fn update_todo(
+        &self,
+        identity: &Identity,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    ) -> Result<Todo> {
+        let scope = self.policy.authorize(identity, Operation::Update)?;
+        let updated = self
+            .todo_store
+            .update_todo(identity, scope, id, todo, version)
+            .await?;
+        let todo = updated.todo.clone();
+
+        self.feed
+            .publish(identity, &todo, TodoEvent::changed(updated));
+        Ok(todo)
     }
 
-    async fn get_todo(&self, id: u32) -> Result<Todo> {
-        Ok(self.todo_store.get_todo(id).await?)
+    #[instrument(
+        name = "TodoService::patch_todo",
+        skip_all,
+        fields(user.id = %identity.user_id, todo.id = id)
+    )]
+    async fn patch_todo(
+        &self,
+        identity: &Identity,
+        id: u32,
+        patch: TodoPatch,
+        version: Option<u32>,
+    ) -> Result<Todo> {
+        let scope = self.policy.authorize(identity, Operation::Patch)?;
+        let updated = self
+            .todo_store
+            .patch_todo(identity, scope, id, patch, version)
+            .await?;
+        let todo = updated.todo.clone();
+
+        self.feed
+            .publish(identity, &todo, TodoEvent::changed(updated));
+        Ok(todo)
     }
 
-    async fn create_todo(&self, todo: TodoInput) -> Result<Todo> {
-        Ok(self.todo_store.create_todo(todo).await?)
+    #[instrument(
+        name = "TodoService::delete_todo",
+        skip_all,
+        fields(user.id = %identity.user_id, todo.id = id)
+    )]
+    async fn delete_todo(&self, identity: &Identity, id: u32, version: Option<u32>) -> Result<()> {
+        let scope = self.policy.authorize(identity, Operation::Delete)?;
+        // Subscribers filter on the owner and state of the todo as it was deleted
+        let deleted = self
+            .todo_store
+            .delete_todo(identity, scope, id, version)
+            .await?;
+
+        if let Some(todo) = deleted {
+            self.feed
+                .publish(identity, &todo, TodoEvent::Deleted { id });
+        }
+        Ok(())
     }
 
-    async fn update_todo(&self, id: u32, todo: TodoInput) -> Result<Todo> {
-        Ok(self.todo_store.update_todo(id, todo).await?)
+    #[instrument(
+        name = "TodoService::batch_todos",
+        skip_all,
+        fields(user.id = %identity.user_id, batch.size = operations.len())
+    )]
+    async fn batch_todos(
+        &self,
+        identity: &Identity,
+        mode: BatchMode,
+        operations: Vec<TodoOperation>,
+    ) -> Result<Vec<Result<TodoWritten>>> {
+        // Operations failing validation or authorization never reach the store
+        let checked: Vec<Result<(Scope, TodoOperation)>> = operations
+            .into_iter()
+            .map(|operation| self.check(identity, operation))
+            .collect();
+
+        let first_failed = checked.iter().position(Result::is_err);
+        if let (BatchMode::AllOrNothing, Some(failed)) = (mode, first_failed) {
+            let len = checked.len();
+            let error = checked
+                .into_iter()
+                .nth(failed)
+                .and_then(Result::err)
+                .unwrap_or_else(Error::batch_aborted);
+            return Ok(aborted_batch(len, failed, error));
+        }
+
+        let mut results: Vec<Option<Result<TodoWritten>>> = Vec::with_capacity(checked.len());
+        let mut writes = vec![];
+        for result in checked {
+            match result {
+                Ok(write) => {
+                    writes.push(write);
+                    results.push(None);
+                }
+                Err(error) => results.push(Some(Err(error))),
+            }
+        }
+
+        let mut written = self
+            .todo_store
+            .batch_todos(identity, writes, mode)
+            .await?
+            .into_iter();
+        let results: Vec<Result<TodoWritten>> = results
+            .into_iter()
+            .map(|result| {
+                result
+                    .or_else(|| written.next())
+                    .unwrap_or_else(|| Err(Error::batch_aborted()))
+            })
+            .collect();
+
+        for written in results.iter().flatten() {
+            if let (Some(todo), Some(event)) = (written.todo(), TodoEvent::written(written)) {
+                self.feed.publish(identity, todo, event);
+            }
+        }
+        Ok(results)
     }
 
-    async fn delete_todo(&self, id: u32) -> Result<()> {
-        Ok(self.todo_store.delete_todo(id).await?)
+    #[instrument(name = "TodoService::watch_todos", skip_all, fields(user.id = %identity.user_id))]
+    async fn watch_todos(
+        &self,
+        identity: &Identity,
+        state: Option<TodoState>,
+        last_event_id: Option<&str>,
+    ) -> Result<TodoSubscription> {
+        let scope = self.policy.authorize(identity, Operation::List)?;
+        Ok(self.feed.subscribe(identity, scope, state, last_event_id))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{error::Error, model::TodoState, todo_store::inmemory::InMemoryTodoStore};
+    use crate::{
+        error::Error,
+        model::{Role, TodoSort, TodoState},
+        todo_store::inmemory::InMemoryTodoStore,
+    };
 
     use super::*;
 
+    fn alice() -> Identity {
+        Identity::new("alice", None)
+    }
+
     #[tokio::test]
     async fn list_todos_should_return_all_items() {
         let todo_store = InMemoryTodoStore::new();
@@ -84,11 +357,65 @@ mod tests {
             state: TodoState::Closed,
         };
 
-        todo_service.create_todo(todo1).await.unwrap();
-        todo_service.create_todo(todo2).await.unwrap();
+        todo_service.create_todo(&alice(), todo1).await.unwrap();
+        todo_service.create_todo(&alice(), todo2).await.unwrap();
+
+        let result = todo_service
+            .list_todos(&alice(), TodoQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(result.items.len(), 2);
+        assert_eq!(result.total_count, 2);
+    }
+
+    #[tokio::test]
+    async fn list_todos_should_filter_and_paginate() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+
+        for (text, state) in [
+            ("Buy milk", TodoState::Opened),
+            ("Buy bread", TodoState::Opened),
+            ("Buy eggs", TodoState::Closed),
+            ("Walk the dog", TodoState::Opened),
+            ("Buy butter", TodoState::Opened),
+        ] {
+            let todo = TodoInput {
+                text: text.to_owned(),
+                state,
+            };
+            todo_service.create_todo(&alice(), todo).await.unwrap();
+        }
+
+        let query = TodoQuery {
+            limit: 2,
+            state: Some(TodoState::Opened),
+            text_contains: Some("Buy".to_owned()),
+            sort: TodoSort::IdDesc,
+            ..TodoQuery::default()
+        };
+        let first_page = todo_service
+            .list_todos(&alice(), query.clone())
+            .await
+            .unwrap();
+        let second_page = todo_service
+            .list_todos(
+                &alice(),
+                TodoQuery {
+                    after: first_page.next_cursor.clone(),
+                    ..query
+                },
+            )
+            .await
+            .unwrap();
 
-        let result = todo_service.list_todos().await.unwrap();
-        assert_eq!(result.len(), 2);
+        let texts = |page: &TodoPage| -> Vec<String> {
+            page.items.iter().map(|todo| todo.text.clone()).collect()
+        };
+        assert_eq!(first_page.total_count, 3);
+        assert_eq!(texts(&first_page), vec!["Buy butter", "Buy bread"]);
+        assert_eq!(texts(&second_page), vec!["Buy milk"]);
+        assert_eq!(second_page.next_cursor, None);
     }
 
     #[tokio::test]
@@ -100,11 +427,154 @@ mod tests {
             text: "First Test Item".to_owned(),
             state: TodoState::Opened,
         };
-        let inserted_todo = todo_service.create_todo(todo).await.unwrap();
-        let result = todo_service.list_todos().await.unwrap();
+        let inserted_todo = todo_service.create_todo(&alice(), todo).await.unwrap();
+        let result = todo_service
+            .list_todos(&alice(), TodoQuery::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.items.len(), 1);
+        assert_eq!(result.items.first().unwrap().to_owned(), inserted_todo);
+    }
+
+    #[tokio::test]
+    async fn patch_todo_should_only_change_supplied_fields() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+
+        let todo = TodoInput {
+            text: "First Test Item".to_owned(),
+            state: TodoState::Opened,
+        };
+        let inserted_todo = todo_service.create_todo(&alice(), todo).await.unwrap();
+        let patch: TodoPatch = serde_json::from_str(r#"{"state": "Closed"}"#).unwrap();
+        let result = todo_service
+            .patch_todo(&alice(), inserted_todo.id, patch, None)
+            .await
+            .unwrap();
 
-        assert_eq!(result.len(), 1);
-        assert_eq!(result.first().unwrap().to_owned(), inserted_todo);
+        assert_eq!(result.text, inserted_todo.text);
+        assert_eq!(result.state, TodoState::Closed);
+        assert!(result.closed_at.is_some());
+        assert_eq!(result.created_by, Some("alice".to_owned()));
+        assert_eq!(result.updated_by, Some("alice".to_owned()));
+        assert!(serde_json::from_str::<TodoPatch>(r#"{"text": null}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn update_todo_should_reject_stale_version() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+
+        let todo = TodoInput {
+            text: "First Test Item".to_owned(),
+            state: TodoState::Opened,
+        };
+        let inserted_todo = todo_service
+            .create_todo(&alice(), todo.clone())
+            .await
+            .unwrap();
+        let updated_todo = todo_service
+            .update_todo(
+                &alice(),
+                inserted_todo.id,
+                todo.clone(),
+                Some(inserted_todo.version),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated_todo.version, inserted_todo.version + 1);
+
+        match todo_service
+            .update_todo(
+                &alice(),
+                inserted_todo.id,
+                todo,
+                Some(inserted_todo.version),
+            )
+            .await
+        {
+            Err(Error::PreconditionFailed(_)) => {}
+            _ => panic!("The stale version should be rejected."),
+        }
+        match todo_service
+            .delete_todo(&alice(), inserted_todo.id, Some(inserted_todo.version))
+            .await
+        {
+            Err(Error::PreconditionFailed(_)) => {}
+            _ => panic!("The stale version should be rejected."),
+        }
+    }
+
+    #[tokio::test]
+    async fn create_todo_should_not_reuse_ids_of_deleted_items() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+
+        let todo = TodoInput {
+            text: "First Test Item".to_owned(),
+            state: TodoState::Opened,
+        };
+        let deleted_todo = todo_service
+            .create_todo(&alice(), todo.clone())
+            .await
+            .unwrap();
+        todo_service
+            .delete_todo(&alice(), deleted_todo.id, None)
+            .await
+            .unwrap();
+        let inserted_todo = todo_service.create_todo(&alice(), todo).await.unwrap();
+
+        assert_ne!(inserted_todo.id, deleted_todo.id);
+        assert_ne!(inserted_todo.etag(), deleted_todo.etag());
+    }
+
+    #[tokio::test]
+    async fn delete_todo_should_publish_the_todo_as_it_was_deleted() {
+        use crate::events::feed::FeedItem;
+
+        let todo_service = TodoService::new(Arc::new(InMemoryTodoStore::new()));
+        let mut subscription = todo_service
+            .watch_todos(&alice(), Some(TodoState::Opened), None)
+            .await
+            .unwrap();
+        let todo = TodoInput {
+            text: "Test Item".to_owned(),
+            state: TodoState::Opened,
+        };
+
+        let deleted_todo = todo_service
+            .create_todo(&alice(), todo.clone())
+            .await
+            .unwrap();
+        // Deleting a missing todo is not a change
+        todo_service.delete_todo(&alice(), 42, None).await.unwrap();
+        todo_service
+            .delete_todo(&alice(), deleted_todo.id, None)
+            .await
+            .unwrap();
+        let inserted_todo = todo_service.create_todo(&alice(), todo).await.unwrap();
+
+        let mut events = vec![];
+        for _ in 0..3 {
+            match subscription.next().await {
+                Some(FeedItem::Change(change)) => {
+                    assert_eq!(change.state, TodoState::Opened);
+                    events.push(change.event);
+                }
+                other => panic!("Unexpected feed item {:?}", other),
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                TodoEvent::Created(deleted_todo.clone()),
+                TodoEvent::Deleted {
+                    id: deleted_todo.id
+                },
+                TodoEvent::Created(inserted_todo),
+            ]
+        );
     }
 
     #[tokio::test]
@@ -116,8 +586,11 @@ mod tests {
             text: "First Test Item".to_owned(),
             state: TodoState::Opened,
         };
-        let inserted_todo = todo_service.create_todo(todo).await.unwrap();
-        let result = todo_service.get_todo(inserted_todo.id).await.unwrap();
+        let inserted_todo = todo_service.create_todo(&alice(), todo).await.unwrap();
+        let result = todo_service
+            .get_todo(&alice(), inserted_todo.id)
+            .await
+            .unwrap();
 
         assert_eq!(result, inserted_todo);
     }
@@ -127,15 +600,149 @@ mod tests {
         let todo_store = InMemoryTodoStore::new();
         let todo_service = TodoService::new(Arc::new(todo_store));
 
-        match todo_service.get_todo(999).await {
+        match todo_service.get_todo(&alice(), 999).await {
             Err(Error::ResourceNotFound { name, id }) => {
                 assert_eq!(name, "todo".to_owned());
                 assert_eq!(id, 999);
             }
-            _ => assert!(
-                false,
+            _ => panic!(
                 "The test should not hit this branch. We expect the action to return an error."
             ),
         }
     }
+
+    #[tokio::test]
+    async fn todos_should_be_scoped_to_their_owner() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+
+        let todo = TodoInput {
+            text: "First Test Item".to_owned(),
+            state: TodoState::Opened,
+        };
+        let inserted_todo = todo_service
+            .create_todo(&alice(), todo.clone())
+            .await
+            .unwrap();
+
+        for other in [
+            Identity::new("bob", None),
+            Identity::new("alice", Some("acme")),
+        ] {
+            let page = todo_service
+                .list_todos(&other, TodoQuery::default())
+                .await
+                .unwrap();
+            assert_eq!(page.total_count, 0);
+            assert!(matches!(
+                todo_service.get_todo(&other, inserted_todo.id).await,
+                Err(Error::ResourceNotFound { .. })
+            ));
+            assert!(matches!(
+                todo_service
+                    .update_todo(&other, inserted_todo.id, todo.clone(), None)
+                    .await,
+                Err(Error::ResourceNotFound { .. })
+            ));
+            todo_service
+                .delete_todo(&other, inserted_todo.id, None)
+                .await
+                .unwrap();
+        }
+
+        let result = todo_service
+            .get_todo(&alice(), inserted_todo.id)
+            .await
+            .unwrap();
+        assert_eq!(result, inserted_todo);
+    }
+
+    #[tokio::test]
+    async fn todo_service_should_enforce_role_policies() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+        let reader = Identity::new("carol", None).with_roles(vec![Role::Reader]);
+        let admin = Identity::new("dave", None).with_roles(vec![Role::Admin]);
+
+        let todo = TodoInput {
+            text: "First Test Item".to_owned(),
+            state: TodoState::Opened,
+        };
+        match todo_service.create_todo(&reader, todo.clone()).await {
+            Err(Error::Forbidden(_)) => {}
+            _ => panic!("Readers should not be able to create todos."),
+        }
+
+        let inserted_todo = todo_service.create_todo(&alice(), todo).await.unwrap();
+        assert!(matches!(
+            todo_service.get_todo(&reader, inserted_todo.id).await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+        assert_eq!(
+            todo_service
+                .list_todos(&admin, TodoQuery::default())
+                .await
+                .unwrap()
+                .items,
+            vec![inserted_todo.clone()]
+        );
+
+        todo_service
+            .delete_todo(&admin, inserted_todo.id, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            todo_service.get_todo(&alice(), inserted_todo.id).await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn batch_todos_should_check_every_operation_on_its_own() {
+        let todo_store = InMemoryTodoStore::new();
+        let todo_service = TodoService::new(Arc::new(todo_store));
+        let operations = || {
+            vec![
+                TodoOperation::Create {
+                    todo: TodoInput {
+                        text: "Valid".to_owned(),
+                        state: TodoState::Opened,
+                    },
+                },
+                TodoOperation::Create {
+                    todo: TodoInput {
+                        text: "".to_owned(),
+                        state: TodoState::Opened,
+                    },
+                },
+                TodoOperation::Delete {
+                    id: 42,
+                    version: None,
+                },
+            ]
+        };
+
+        let results = todo_service
+            .batch_todos(&alice(), BatchMode::AllOrNothing, operations())
+            .await
+            .unwrap();
+        assert!(matches!(results[0], Err(Error::Aborted(_))));
+        assert!(matches!(results[1], Err(Error::Validator(_))));
+        assert!(matches!(results[2], Err(Error::Aborted(_))));
+
+        let results = todo_service
+            .batch_todos(&alice(), BatchMode::BestEffort, operations())
+            .await
+            .unwrap();
+        assert!(matches!(results[0], Ok(TodoWritten::Created(_))));
+        assert!(matches!(results[1], Err(Error::Validator(_))));
+        assert_eq!(results[2].as_ref().unwrap(), &TodoWritten::Deleted(None));
+
+        let reader = Identity::new("carol", None).with_roles(vec![Role::Reader]);
+        let results = todo_service
+            .batch_todos(&reader, BatchMode::BestEffort, operations())
+            .await
+            .unwrap();
+        assert!(matches!(results[0], Err(Error::Forbidden(_))));
+    }
 }
@@ -0,0 +1,513 @@
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    header::CONTENT_TYPE,
+    redirect::Policy,
+    Client,
+};
+use sha2::Sha256;
+use sqlx::{sqlite::SqlitePool, FromRow};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, warn};
+use url::Url;
+
+use super::{host_ip, is_public};
+use crate::config::WebhooksConfig;
+use crate::error::Error;
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+pub const EVENT_ID_HEADER: &str = "x-webhook-event-id";
+pub const EVENT_TYPE_HEADER: &str = "x-webhook-event";
+
+// Deliveries picked up per round, and how many of them are in flight at once
+const BATCH_SIZE: u32 = 100;
+const CONCURRENCY: usize = 10;
+
+// Signature of a delivery, the hex encoded HMAC-SHA256 of `<timestamp>.<body>` keyed with the
+// webhook secret. Receivers recompute it and reject stale timestamps to prevent replays.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// Resolves the hosts of the webhooks and refuses to connect when any of their addresses is not
+// public. The client connects to the addresses checked here, a host can not be made to resolve
+// elsewhere in between.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            match addrs.iter().find(|addr| !is_public(addr.ip())) {
+                Some(addr) => Err(format!(
+                    "{} resolves to the non-public address {}",
+                    name.as_str(),
+                    addr.ip()
+                )
+                .into()),
+                None => Ok(Box::new(addrs.into_iter()) as Addrs),
+            }
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct DueDelivery {
+    id: i64,
+    webhook_id: u32,
+    event_id: i64,
+    event_type: String,
+    payload: String,
+    attempts: u32,
+    url: String,
+    secret: String,
+}
+
+// Background task posting the queued deliveries. Failed deliveries are retried with
+// exponential backoff and moved to the dead letters once `max_attempts` is reached.
+pub struct WebhookWorker {
+    pool: SqlitePool,
+    client: Client,
+    allow_private_targets: bool,
+    max_attempts: u32,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    poll_interval: Duration,
+}
+
+impl WebhookWorker {
+    pub fn new(pool: SqlitePool, config: &WebhooksConfig) -> Result<Self, reqwest::Error> {
+        Self::build(pool, config, false)
+    }
+
+    fn build(
+        pool: SqlitePool,
+        config: &WebhooksConfig,
+        allow_private_targets: bool,
+    ) -> Result<Self, reqwest::Error> {
+        // Redirects are failed deliveries, the webhook has to be registered with the final url.
+        // Proxies would connect to the targets without their addresses being checked.
+        let mut client = Client::builder()
+            .timeout(config.request_timeout)
+            .redirect(Policy::none())
+            .no_proxy();
+        if !allow_private_targets {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+
+        Ok(Self {
+            pool,
+            client: client.build()?,
+            allow_private_targets,
+            max_attempts: config.max_attempts,
+            backoff_base: config.backoff_base,
+            backoff_max: config.backoff_max,
+            poll_interval: config.poll_interval,
+        })
+    }
+
+    // Attempts the deliveries which are due and returns how many were attempted
+    pub async fn deliver_due(&self) -> Result<usize, Error> {
+        let due = sqlx::query_as::<_, DueDelivery>(
+            "select d.id, d.webhook_id, d.event_id, d.event_type, d.payload, d.attempts, \
+             w.url, w.secret from webhook_deliveries d join webhooks w on w.id = d.webhook_id \
+             where d.delivered_at is null and d.failed_at is null and d.next_attempt_at <= ? \
+             order by d.id limit ?",
+        )
+        .bind(Utc::now())
+        .bind(BATCH_SIZE)
+        .fetch_all(&self.pool)
+        .await?;
+        let attempted = due.len();
+
+        let results: Vec<Result<(), Error>> = stream::iter(due)
+            .map(|delivery| self.deliver(delivery))
+            .buffer_unordered(CONCURRENCY)
+            .collect()
+            .await;
+        results.into_iter().collect::<Result<(), Error>>()?;
+
+        Ok(attempted)
+    }
+
+    async fn deliver(&self, delivery: DueDelivery) -> Result<(), Error> {
+        let attempt = delivery.attempts + 1;
+        let timestamp = Utc::now().timestamp();
+        let started = Instant::now();
+
+        let response = match self.refused_target(&delivery.url) {
+            Some(refused) => Err(refused),
+            None => self
+                .client
+                .post(&delivery.url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(EVENT_ID_HEADER, delivery.event_id)
+                .header(EVENT_TYPE_HEADER, &delivery.event_type)
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(
+                    SIGNATURE_HEADER,
+                    sign(&delivery.secret, timestamp, &delivery.payload),
+                )
+                .body(delivery.payload.clone())
+                .send()
+                .await
+                .map_err(|e| error_chain(&e)),
+        };
+        let duration_ms = started.elapsed().as_millis() as i64;
+
+        let (status_code, failure) = match response {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (
+                Some(response.status()),
+                Some(format!("Unexpected status {}", response.status())),
+            ),
+            Err(failure) => (None, Some(failure)),
+        };
+
+        let now = Utc::now();
+        sqlx::query(
+            "insert into webhook_delivery_attempts \
+             (webhook_id, event_id, attempt, status_code, error, duration_ms, attempted_at) \
+             values (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(delivery.webhook_id)
+        .bind(delivery.event_id)
+        .bind(attempt)
+        .bind(status_code.map(|status| status.as_u16()))
+        .bind(&failure)
+        .bind(duration_ms)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        match failure {
+            None => {
+                debug!(
+                    "Delivered event {} to webhook {}",
+                    delivery.event_id, delivery.webhook_id
+                );
+                sqlx::query(
+                    "update webhook_deliveries set attempts = ?, delivered_at = ? where id = ?",
+                )
+                .bind(attempt)
+                .bind(now)
+                .bind(delivery.id)
+                .execute(&self.pool)
+                .await?;
+            }
+            Some(failure) if attempt >= self.max_attempts => {
+                warn!(
+                    "Giving up on event {} for webhook {} after {} attempts: {}",
+                    delivery.event_id, delivery.webhook_id, attempt, failure
+                );
+                self.dead_letter(&delivery, attempt, &failure, now).await?;
+            }
+            Some(failure) => {
+                let next_attempt_at = now + self.backoff(attempt);
+                debug!(
+                    "Failed to deliver event {} to webhook {}, will retry at {}: {}",
+                    delivery.event_id, delivery.webhook_id, next_attempt_at, failure
+                );
+                sqlx::query(
+                    "update webhook_deliveries set attempts = ?, next_attempt_at = ? where id = ?",
+                )
+                .bind(attempt)
+                .bind(next_attempt_at)
+                .bind(delivery.id)
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Urls with an ip address are not resolved, their address is checked before every attempt
+    fn refused_target(&self, url: &str) -> Option<String> {
+        if self.allow_private_targets {
+            return None;
+        }
+        let ip = Url::parse(url).ok().as_ref().and_then(host_ip)?;
+
+        (!is_public(ip)).then(|| format!("{} is not a public address", ip))
+    }
+
+    async fn dead_letter(
+        &self,
+        delivery: &DueDelivery,
+        attempts: u32,
+        failure: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "insert into webhook_dead_letters \
+             (webhook_id, event_id, url, payload, attempts, last_error, failed_at) \
+             values (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(delivery.webhook_id)
+        .bind(delivery.event_id)
+        .bind(&delivery.url)
+        .bind(&delivery.payload)
+        .bind(attempts)
+        .bind(failure)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("update webhook_deliveries set attempts = ?, failed_at = ? where id = ?")
+            .bind(attempts)
+            .bind(now)
+            .bind(delivery.id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Delay after the given failed attempt: base, 2 x base, 4 x base, ... up to the maximum
+    fn backoff(&self, attempt: u32) -> chrono::Duration {
+        let delay = 2u32
+            .checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.backoff_base.checked_mul(factor))
+            .map_or(self.backoff_max, |delay| delay.min(self.backoff_max));
+
+        chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::weeks(52))
+    }
+
+    // Delivers until the task is aborted, full batches are followed up immediately
+    pub async fn run(self) {
+        loop {
+            match self.deliver_due().await {
+                Ok(attempted) if attempted == BATCH_SIZE as usize => continue,
+                Ok(_) => {}
+                Err(e) => error!("Failed to deliver webhooks: {}", e),
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+// Failures of the resolver and the connection are only told by the sources of the error
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        message = format!("{}: {}", message, error);
+        source = error.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use crate::model::Identity;
+    use crate::webhooks::{tests::event, WebhookInput, WebhookStore};
+    use axum::{
+        body::Bytes,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Extension, Router,
+    };
+    use std::{
+        net::{SocketAddr, TcpListener},
+        sync::{Arc, Mutex},
+    };
+
+    // Receiver failing the first `failures` requests, and accepting correctly signed ones after
+    #[derive(Default)]
+    struct Receiver {
+        failures: usize,
+        requests: Mutex<Vec<(HeaderMap, Bytes)>>,
+    }
+
+    async fn receive(
+        Extension(receiver): Extension<Arc<Receiver>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut requests = receiver.requests.lock().unwrap();
+        requests.push((headers, body));
+        match requests.len() > receiver.failures {
+            true => StatusCode::NO_CONTENT,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn serve(receiver: Arc<Receiver>) -> SocketAddr {
+        let app = Router::new()
+            .route("/hooks", post(receive))
+            .layer(Extension(receiver));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        addr
+    }
+
+    fn config(max_attempts: u32) -> WebhooksConfig {
+        WebhooksConfig {
+            enabled: true,
+            max_attempts,
+            backoff_base: Duration::ZERO,
+            backoff_max: Duration::ZERO,
+            request_timeout: Duration::from_secs(5),
+            poll_interval: Duration::from_millis(10),
+        }
+    }
+
+    // Webhooks are registered straight with the store, which leaves their urls unchecked
+    async fn setup(urls: &[String]) -> (SqlitePool, WebhookStore, Vec<String>) {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = WebhookStore::new(pool.clone());
+        let mut secrets = vec![];
+        for url in urls {
+            let input = WebhookInput {
+                url: url.clone(),
+                events: vec![],
+            };
+            let webhook = store
+                .create(&Identity::new("alice", Some("acme")), &input)
+                .await
+                .unwrap();
+            secrets.push(webhook.secret.unwrap());
+        }
+        store
+            .enqueue(&event(1, "TodoCreated", "acme"))
+            .await
+            .unwrap();
+
+        (pool, store, secrets)
+    }
+
+    #[tokio::test]
+    async fn worker_should_retry_failed_deliveries_with_a_valid_signature() {
+        let receiver = Arc::new(Receiver {
+            failures: 1,
+            ..Receiver::default()
+        });
+        let url = format!("http://{}/hooks", serve(receiver.clone()));
+        let (pool, store, secrets) = setup(&[url]).await;
+        let secret = &secrets[0];
+        // The receiver listens on the loopback interface
+        let worker = WebhookWorker::build(pool, &config(3), true).unwrap();
+
+        assert_eq!(worker.deliver_due().await.unwrap(), 1);
+        assert_eq!(worker.deliver_due().await.unwrap(), 1);
+        assert_eq!(worker.deliver_due().await.unwrap(), 0);
+
+        let requests = receiver.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        for (headers, body) in &requests {
+            let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+            let body = std::str::from_utf8(body).unwrap();
+            assert_eq!(headers[SIGNATURE_HEADER], sign(secret, timestamp, body));
+            assert_eq!(headers[EVENT_TYPE_HEADER], "TodoCreated");
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(body).unwrap()["id"],
+                1
+            );
+        }
+
+        let alice = Identity::new("alice", Some("acme"));
+        let webhook_id = store.list(&alice).await.unwrap()[0].id;
+        let attempts = store.attempts(&alice, webhook_id).await.unwrap();
+        let statuses: Vec<_> = attempts
+            .iter()
+            .map(|a| (a.attempt, a.status_code))
+            .collect();
+        assert_eq!(statuses, vec![(2, Some(204)), (1, Some(503))]);
+    }
+
+    #[tokio::test]
+    async fn worker_should_dead_letter_deliveries_after_the_last_attempt() {
+        let receiver = Arc::new(Receiver {
+            failures: usize::MAX,
+            ..Receiver::default()
+        });
+        let url = format!("http://{}/hooks", serve(receiver.clone()));
+        let (pool, _, _) = setup(&[url]).await;
+        let worker = WebhookWorker::build(pool.clone(), &config(2), true).unwrap();
+
+        for _ in 0..3 {
+            worker.deliver_due().await.unwrap();
+        }
+
+        assert_eq!(receiver.requests.lock().unwrap().len(), 2);
+        let (attempts, last_error): (u32, String) =
+            sqlx::query_as("select attempts, last_error from webhook_dead_letters")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(attempts, 2);
+        assert!(last_error.contains("503"), "{}", last_error);
+    }
+
+    #[tokio::test]
+    async fn worker_should_refuse_to_deliver_to_non_public_addresses() {
+        let receiver = Arc::new(Receiver::default());
+        let addr = serve(receiver.clone());
+        let (pool, store, _) = setup(&[
+            format!("http://{}/hooks", addr),
+            format!("http://localhost:{}/hooks", addr.port()),
+        ])
+        .await;
+        let worker = WebhookWorker::new(pool, &config(3)).unwrap();
+
+        assert_eq!(worker.deliver_due().await.unwrap(), 2);
+        assert!(receiver.requests.lock().unwrap().is_empty());
+
+        let alice = Identity::new("alice", Some("acme"));
+        let mut errors = vec![];
+        for webhook in store.list(&alice).await.unwrap() {
+            let attempts = store.attempts(&alice, webhook.id).await.unwrap();
+            assert_eq!(attempts[0].status_code, None);
+            errors.push(attempts[0].error.clone().unwrap());
+        }
+        assert_eq!(errors[0], "127.0.0.1 is not a public address");
+        assert!(
+            errors[1].contains("localhost resolves to the non-public address"),
+            "{}",
+            errors[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn backoff_should_double_up_to_the_maximum() {
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let worker = WebhookWorker::new(
+            pool,
+            &WebhooksConfig {
+                backoff_base: Duration::from_secs(1),
+                backoff_max: Duration::from_secs(60),
+                ..config(10)
+            },
+        )
+        .unwrap();
+
+        let delays: Vec<i64> = [1, 2, 3, 7, 40]
+            .into_iter()
+            .map(|attempt| worker.backoff(attempt).num_seconds())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 60, 60]);
+    }
+}
@@ -0,0 +1,418 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow};
+use std::net::IpAddr;
+use url::{Host, Url};
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
+
+use crate::error::Error;
+use crate::events::{EventPublisher, OutboxEvent, PublishError, EVENT_TYPES};
+use crate::model::Identity;
+use crate::todo_store::tenant_key;
+
+pub mod delivery;
+
+const SECRET_BYTES: usize = 32;
+// Latest attempts listed for a webhook
+const MAX_LISTED_ATTEMPTS: u32 = 100;
+
+#[derive(Deserialize, Clone, Validate, ToSchema)]
+pub struct WebhookInput {
+    /// Endpoint the events are posted to
+    #[validate(custom = "validate_url")]
+    #[schema(example = "https://example.com/hooks/todos")]
+    pub url: String,
+    /// Event types to deliver, every event when empty
+    #[serde(default)]
+    #[validate(custom = "validate_event_types")]
+    pub events: Vec<String>,
+}
+
+// Host names are only checked once resolved, before every delivery
+fn validate_url(url: &str) -> Result<(), ValidationError> {
+    let url = match Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => url,
+        _ => return Err(invalid("url", "Must be an absolute http or https url")),
+    };
+
+    let local_name = matches!(url.host(), Some(Host::Domain(domain)) if is_local_name(domain));
+    match (local_name, host_ip(&url)) {
+        (true, _) => Err(invalid("url", "Must not point to a non-public address")),
+        (_, Some(ip)) if !is_public(ip) => {
+            Err(invalid("url", "Must not point to a non-public address"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Webhooks must not reach into the network the service runs in, such as the cloud metadata
+// endpoint, the database or the service itself
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // Reserved and broadcast, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+// Address of urls with an ip address as host, host names are not resolved
+pub(crate) fn host_ip(url: &Url) -> Option<IpAddr> {
+    match url.host()? {
+        Host::Ipv4(ip) => Some(ip.into()),
+        Host::Ipv6(ip) => Some(ip.into()),
+        Host::Domain(_) => None,
+    }
+}
+
+// Names which always resolve to the loopback interface (RFC 6761)
+fn is_local_name(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    domain == "localhost" || domain.ends_with(".localhost")
+}
+
+fn validate_event_types(events: &[String]) -> Result<(), ValidationError> {
+    match events
+        .iter()
+        .all(|event| EVENT_TYPES.contains(&event.as_str()))
+    {
+        true => Ok(()),
+        false => Err(invalid(
+            "event_type",
+            "Must be one of the known event types",
+        )),
+    }
+}
+
+fn invalid(code: &'static str, message: &'static str) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    error
+}
+
+// Deliveries are signed with the secret, which is only returned when the webhook is created
+#[derive(Serialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct Webhook {
+    pub id: u32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(FromRow)]
+struct WebhookRow {
+    id: u32,
+    url: String,
+    event_types: String,
+    created_by: String,
+    created_at: DateTime<Utc>,
+}
+
+impl From<WebhookRow> for Webhook {
+    fn from(row: WebhookRow) -> Self {
+        Self {
+            id: row.id,
+            url: row.url,
+            events: row
+                .event_types
+                .split(',')
+                .filter(|event| !event.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            created_by: row.created_by,
+            created_at: row.created_at,
+            secret: None,
+        }
+    }
+}
+
+const WEBHOOK_COLUMNS: &str = "id, url, event_types, created_by, created_at";
+
+// Single request made to deliver an event, the error tells why a delivery failed
+#[derive(Serialize, Clone, FromRow, Debug, PartialEq, ToSchema)]
+pub struct DeliveryAttempt {
+    pub id: i64,
+    pub event_id: i64,
+    pub attempt: u32,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub attempted_at: DateTime<Utc>,
+}
+
+// Webhooks are registered per tenant, deliveries are queued for every webhook of the tenant
+// the event belongs to and interested in its type
+pub struct WebhookStore {
+    pool: SqlitePool,
+}
+
+impl WebhookStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        identity: &Identity,
+        input: &WebhookInput,
+    ) -> Result<Webhook, Error> {
+        let mut secret = [0u8; SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = hex::encode(secret);
+
+        let row = sqlx::query_as::<_, WebhookRow>(&format!(
+            "insert into webhooks (tenant_id, url, event_types, secret, created_by, created_at) \
+             values (?, ?, ?, ?, ?, ?) returning {}",
+            WEBHOOK_COLUMNS
+        ))
+        .bind(tenant_key(identity))
+        .bind(&input.url)
+        .bind(input.events.join(","))
+        .bind(&secret)
+        .bind(&identity.user_id)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Webhook {
+            secret: Some(secret),
+            ..row.into()
+        })
+    }
+
+    pub async fn list(&self, identity: &Identity) -> Result<Vec<Webhook>, Error> {
+        let rows = sqlx::query_as::<_, WebhookRow>(&format!(
+            "select {} from webhooks where tenant_id = ? order by id",
+            WEBHOOK_COLUMNS
+        ))
+        .bind(tenant_key(identity))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Webhook::from).collect())
+    }
+
+    // Pending deliveries and the attempts go along with the webhook, dead letters are kept
+    pub async fn delete(&self, identity: &Identity, id: u32) -> Result<(), Error> {
+        let result = sqlx::query("delete from webhooks where id = ? and tenant_id = ?")
+            .bind(id)
+            .bind(tenant_key(identity))
+            .execute(&self.pool)
+            .await?;
+
+        match result.rows_affected() {
+            0 => Err(not_found(id)),
+            _ => Ok(()),
+        }
+    }
+
+    // Latest attempts first
+    pub async fn attempts(
+        &self,
+        identity: &Identity,
+        id: u32,
+    ) -> Result<Vec<DeliveryAttempt>, Error> {
+        sqlx::query("select id from webhooks where id = ? and tenant_id = ?")
+            .bind(id)
+            .bind(tenant_key(identity))
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| not_found(id))?;
+
+        let attempts = sqlx::query_as::<_, DeliveryAttempt>(
+            "select id, event_id, attempt, status_code, error, duration_ms, attempted_at \
+             from webhook_delivery_attempts where webhook_id = ? order by id desc limit ?",
+        )
+        .bind(id)
+        .bind(MAX_LISTED_ATTEMPTS)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(attempts)
+    }
+
+    // Queues the event for the interested webhooks and returns how many there are.
+    // Events queued before are skipped, the relay may hand the same event over again.
+    pub async fn enqueue(&self, event: &OutboxEvent) -> Result<u64, Error> {
+        let payload = serde_json::to_string(event).map_err(|e| Error::Unexpected(e.into()))?;
+
+        let result = sqlx::query(
+            "insert or ignore into webhook_deliveries \
+             (webhook_id, event_id, event_type, payload, next_attempt_at) \
+             select id, ?, ?, ?, ? from webhooks where tenant_id = ? \
+             and (event_types = '' or instr(',' || event_types || ',', ',' || ? || ',') > 0)",
+        )
+        .bind(event.id)
+        .bind(&event.event_type)
+        .bind(payload)
+        .bind(Utc::now())
+        .bind(&event.tenant_id)
+        .bind(&event.event_type)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+fn not_found(id: u32) -> Error {
+    Error::ResourceNotFound {
+        name: "Webhook".to_owned(),
+        id,
+    }
+}
+
+// Lets the outbox relay feed the deliveries, the worker takes it from there
+#[async_trait]
+impl EventPublisher for WebhookStore {
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), PublishError> {
+        self.enqueue(event)
+            .await
+            .map(|_| ())
+            .map_err(|e| PublishError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SQLITE_MIGRATOR;
+    use serde_json::json;
+
+    pub(super) fn event(id: i64, event_type: &str, tenant_id: &str) -> OutboxEvent {
+        OutboxEvent {
+            id,
+            event_type: event_type.to_owned(),
+            todo_id: 1,
+            tenant_id: tenant_id.to_owned(),
+            occurred_at: Utc::now(),
+            data: json!({ "id": 1 }),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhooks_should_only_get_the_events_of_their_tenant_and_types() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let store = WebhookStore::new(pool);
+        let alice = Identity::new("alice", Some("acme"));
+        let input = |events: &[&str]| WebhookInput {
+            url: "https://example.com/hooks".to_owned(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+        };
+
+        let all = store.create(&alice, &input(&[])).await.unwrap();
+        let closed = store.create(&alice, &input(&["TodoClosed"])).await.unwrap();
+        assert_eq!(all.secret.as_ref().map(String::len), Some(2 * SECRET_BYTES));
+        assert_eq!(closed.events, vec!["TodoClosed"]);
+
+        assert_eq!(
+            store
+                .enqueue(&event(1, "TodoCreated", "acme"))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .enqueue(&event(2, "TodoClosed", "acme"))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store
+                .enqueue(&event(2, "TodoClosed", "acme"))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            store
+                .enqueue(&event(3, "TodoClosed", "other"))
+                .await
+                .unwrap(),
+            0
+        );
+
+        let bob = Identity::new("bob", Some("other"));
+        assert!(store.list(&bob).await.unwrap().is_empty());
+        assert!(matches!(
+            store.delete(&bob, all.id).await,
+            Err(Error::ResourceNotFound { .. })
+        ));
+        store.delete(&alice, all.id).await.unwrap();
+        let listed = store.list(&alice).await.unwrap();
+        assert_eq!(
+            listed,
+            vec![Webhook {
+                secret: None,
+                ..closed
+            }]
+        );
+    }
+
+    #[test]
+    fn input_should_require_an_http_url_and_known_event_types() {
+        let input = |url: &str, event: &str| WebhookInput {
+            url: url.to_owned(),
+            events: vec![event.to_owned()],
+        };
+
+        assert!(input("https://example.com/hooks", "TodoDeleted")
+            .validate()
+            .is_ok());
+        assert!(input("ftp://example.com/hooks", "TodoDeleted")
+            .validate()
+            .is_err());
+        assert!(input("/hooks", "TodoDeleted").validate().is_err());
+        assert!(input("https://93.184.215.14/hooks", "TodoDeleted")
+            .validate()
+            .is_ok());
+        for url in [
+            "http://127.0.0.1:8080/hooks",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.7/hooks",
+            "http://192.168.1.1/hooks",
+            "http://100.64.0.1/hooks",
+            "http://0.0.0.0/hooks",
+            "http://[::1]/hooks",
+            "http://[fd00::1]/hooks",
+            "http://[fe80::1]/hooks",
+            "http://[::ffff:127.0.0.1]/hooks",
+            "http://localhost:8080/hooks",
+            "http://api.localhost./hooks",
+        ] {
+            assert!(input(url, "TodoDeleted").validate().is_err(), "{}", url);
+        }
+        assert!(input("https://example.com/hooks", "TodoArchived")
+            .validate()
+            .is_err());
+    }
+}
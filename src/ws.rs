@@ -0,0 +1,434 @@
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use http_api_problem::{ApiError, HttpApiProblem};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{borrow::Cow, future};
+use tokio::time::{self, Instant};
+use tracing::debug;
+use validator::Validate;
+
+use crate::config::StreamConfig;
+use crate::error::{Error, Result};
+use crate::events::feed::{FeedItem, TodoSubscription};
+use crate::model::{Identity, Todo, TodoInput, TodoState};
+use crate::use_cases::TodoInputPortArc;
+
+// Close code of idle connections and clients not keeping up with their frames (RFC 6455)
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+// Frames sent by the client. Commands may carry a `ref` which is echoed in their reply.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientFrame {
+    Subscribe {
+        #[serde(rename = "ref")]
+        reference: Option<String>,
+        state: Option<TodoState>,
+        last_event_id: Option<String>,
+    },
+    Unsubscribe {
+        #[serde(rename = "ref")]
+        reference: Option<String>,
+    },
+    Create {
+        #[serde(rename = "ref")]
+        reference: Option<String>,
+        todo: TodoInput,
+    },
+    Update {
+        #[serde(rename = "ref")]
+        reference: Option<String>,
+        id: u32,
+        todo: TodoInput,
+        version: Option<u32>,
+    },
+    Delete {
+        #[serde(rename = "ref")]
+        reference: Option<String>,
+        id: u32,
+        version: Option<u32>,
+    },
+}
+
+// Frames sent by the server, errors carry a Problem Details body as returned by the REST api
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Subscribed {
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+    },
+    Unsubscribed {
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+    },
+    Event {
+        id: u64,
+        event: &'static str,
+        data: Value,
+    },
+    // Changes were lost, the client has to reload the todos it shows
+    Resync,
+    Result {
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+        todo: Option<Todo>,
+    },
+    Error {
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
+        problem: HttpApiProblem,
+    },
+}
+
+impl ServerFrame {
+    fn error(reference: Option<String>, error: Error) -> Self {
+        ServerFrame::Error {
+            reference,
+            problem: ApiError::from(error).into_http_api_problem(),
+        }
+    }
+
+    fn from_feed(item: FeedItem) -> Self {
+        match item {
+            FeedItem::Change(change) => ServerFrame::Event {
+                id: change.id,
+                event: change.event.event_type(),
+                data: change.event.data(),
+            },
+            FeedItem::Missed => ServerFrame::Resync,
+        }
+    }
+}
+
+// Connection of a single client. Frames are handled one after another, a client sending faster
+// than its commands complete is held back by the socket. A subscriber reading slower than the
+// changes arrive gets a `resync`, sends blocked for longer than `send_timeout` close the
+// connection. Pings keep the connection alive, it is closed once the client is silent
+// (not even answering pings) for `idle_timeout`.
+pub struct Session {
+    todo_port: TodoInputPortArc,
+    identity: Identity,
+    subscription: Option<TodoSubscription>,
+}
+
+impl Session {
+    pub fn new(todo_port: TodoInputPortArc, identity: Identity) -> Self {
+        Self {
+            todo_port,
+            identity,
+            subscription: None,
+        }
+    }
+
+    pub async fn run(mut self, mut socket: WebSocket, config: StreamConfig) {
+        let mut ping = time::interval_at(
+            Instant::now() + config.heartbeat_interval,
+            config.heartbeat_interval,
+        );
+        let idle = time::sleep(config.idle_timeout);
+        tokio::pin!(idle);
+
+        loop {
+            let frame = tokio::select! {
+                received = socket.recv() => match received {
+                    Some(Ok(message)) => {
+                        idle.as_mut().reset(Instant::now() + config.idle_timeout);
+                        match message {
+                            Message::Text(text) => Some(self.handle(&text).await),
+                            Message::Binary(_) => Some(ServerFrame::error(
+                                None,
+                                Error::InvalidPayload("Frames have to be JSON text".to_owned()),
+                            )),
+                            Message::Close(_) => break,
+                            Message::Ping(_) | Message::Pong(_) => None,
+                        }
+                    }
+                    _ => break,
+                },
+                item = next_change(&mut self.subscription) => match item {
+                    Some(item) => Some(ServerFrame::from_feed(item)),
+                    None => break,
+                },
+                _ = ping.tick() => {
+                    if send(&mut socket, Message::Ping(vec![]), &config).await.is_err() {
+                        break;
+                    }
+                    None
+                },
+                _ = &mut idle => {
+                    debug!("Closing idle websocket of {}", self.identity.user_id);
+                    let _ = send(&mut socket, close("idle timeout"), &config).await;
+                    break;
+                },
+            };
+
+            if let Some(frame) = frame {
+                let text = serde_json::to_string(&frame).expect("frames serialize to json");
+                if send(&mut socket, Message::Text(text), &config)
+                    .await
+                    .is_err()
+                {
+                    debug!(
+                        "Closing websocket of {}, it does not keep up",
+                        self.identity.user_id
+                    );
+                    let _ = send(&mut socket, close("too slow"), &config).await;
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, text: &str) -> ServerFrame {
+        match serde_json::from_str::<ClientFrame>(text) {
+            Ok(frame) => self.execute(frame).await,
+            Err(e) => ServerFrame::error(None, Error::InvalidPayload(e.to_string())),
+        }
+    }
+
+    // Commands go through the same use cases, and thus the same policy, as the REST handlers
+    async fn execute(&mut self, frame: ClientFrame) -> ServerFrame {
+        let identity = &self.identity;
+        let (reference, result) = match frame {
+            ClientFrame::Subscribe {
+                reference,
+                state,
+                last_event_id,
+            } => {
+                let subscription = self
+                    .todo_port
+                    .watch_todos(identity, state, last_event_id.as_deref())
+                    .await;
+                return match subscription {
+                    Ok(subscription) => {
+                        self.subscription = Some(subscription);
+                        ServerFrame::Subscribed { reference }
+                    }
+                    Err(error) => ServerFrame::error(reference, error),
+                };
+            }
+            ClientFrame::Unsubscribe { reference } => {
+                self.subscription = None;
+                return ServerFrame::Unsubscribed { reference };
+            }
+            ClientFrame::Create { reference, todo } => {
+                let result = match todo.validate() {
+                    Ok(_) => self.todo_port.create_todo(identity, todo).await.map(Some),
+                    Err(e) => Err(e.into()),
+                };
+                (reference, result)
+            }
+            ClientFrame::Update {
+                reference,
+                id,
+                todo,
+                version,
+            } => {
+                let result = match todo.validate() {
+                    Ok(_) => self
+                        .todo_port
+                        .update_todo(identity, id, todo, version)
+                        .await
+                        .map(Some),
+                    Err(e) => Err(e.into()),
+                };
+                (reference, result)
+            }
+            ClientFrame::Delete {
+                reference,
+                id,
+                version,
+            } => {
+                let result: Result<Option<Todo>> = self
+                    .todo_port
+                    .delete_todo(identity, id, version)
+                    .await
+                    .map(|_| None);
+                (reference, result)
+            }
+        };
+
+        match result {
+            Ok(todo) => ServerFrame::Result { reference, todo },
+            Err(error) => ServerFrame::error(reference, error),
+        }
+    }
+}
+
+// Waits forever without a subscription, None once the feed is gone
+async fn next_change(subscription: &mut Option<TodoSubscription>) -> Option<FeedItem> {
+    match subscription {
+        Some(subscription) => subscription.next().await,
+        None => future::pending().await,
+    }
+}
+
+async fn send(
+    socket: &mut WebSocket,
+    message: Message,
+    config: &StreamConfig,
+) -> std::result::Result<(), ()> {
+    match time::timeout(config.send_timeout, socket.send(message)).await {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(()),
+    }
+}
+
+fn close(reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CLOSE_GOING_AWAY,
+        reason: Cow::Borrowed(reason),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::ws_handler;
+    use crate::todo_store::inmemory::InMemoryTodoStore;
+    use crate::use_cases::TodoService;
+    use axum::{routing::get, Extension, Router};
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use std::{net::TcpListener, sync::Arc, time::Duration};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        tungstenite::{client::IntoClientRequest, Message as ClientMessage},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    #[test]
+    fn client_frames_should_be_tagged_by_type() {
+        let frame: ClientFrame = serde_json::from_value(json!({
+            "type": "update",
+            "ref": "42",
+            "id": 7,
+            "todo": { "text": "Write tests", "state": "Closed" },
+            "version": 2
+        }))
+        .unwrap();
+        assert!(matches!(
+            frame,
+            ClientFrame::Update {
+                reference: Some(_),
+                id: 7,
+                version: Some(2),
+                ..
+            }
+        ));
+
+        let problem = serde_json::to_value(ServerFrame::error(
+            Some("42".to_owned()),
+            Error::stale_version("todo", 7),
+        ))
+        .unwrap();
+        assert_eq!(problem["type"], "error");
+        assert_eq!(problem["ref"], "42");
+        assert_eq!(problem["problem"]["status"], 412);
+        assert_eq!(
+            problem["problem"]["type"],
+            "type://error.entity.precondition-failed"
+        );
+    }
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    // Sends the frame unless it is null and returns the next frame received
+    async fn exchange(socket: &mut Client, frame: Value) -> Value {
+        if !frame.is_null() {
+            let text = ClientMessage::Text(frame.to_string());
+            socket.send(text).await.unwrap();
+        }
+        match socket.next().await.unwrap().unwrap() {
+            ClientMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Expected a text frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn session_should_run_commands_and_stream_changes() {
+        let todo_port: TodoInputPortArc =
+            Arc::new(TodoService::new(Arc::new(InMemoryTodoStore::new())));
+        let config = StreamConfig {
+            replay_buffer: 10,
+            heartbeat_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_millis(300),
+            send_timeout: Duration::from_secs(1),
+        };
+        let app = Router::new()
+            .route("/api/v1/ws", get(ws_handler))
+            .layer(Extension(todo_port))
+            .layer(Extension(config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/api/v1/ws", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let mut request = url.into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("x-user-id", "alice".parse().unwrap());
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(
+            exchange(&mut socket, json!({ "type": "subscribe" })).await,
+            json!({ "type": "subscribed" })
+        );
+        assert_eq!(
+            exchange(&mut socket, json!({ "type": "unsubscribe", "ref": "u" })).await,
+            json!({ "type": "unsubscribed", "ref": "u" })
+        );
+        assert_eq!(
+            exchange(
+                &mut socket,
+                json!({ "type": "subscribe", "ref": "s", "state": "Opened" })
+            )
+            .await,
+            json!({ "type": "subscribed", "ref": "s" })
+        );
+        let created = exchange(
+            &mut socket,
+            json!({
+                "type": "create",
+                "ref": "1",
+                "todo": { "text": "Write tests", "state": "Opened" }
+            }),
+        )
+        .await;
+        assert_eq!(created["type"], "result");
+        assert_eq!(created["ref"], "1");
+        assert_eq!(created["todo"]["text"], "Write tests");
+
+        let event = exchange(&mut socket, Value::Null).await;
+        assert_eq!(event["type"], "event");
+        assert_eq!(event["event"], "TodoCreated");
+        assert_eq!(event["data"], created["todo"]);
+
+        let error = exchange(
+            &mut socket,
+            json!({
+                "type": "update",
+                "ref": "2",
+                "id": 1,
+                "todo": { "text": "", "state": "Closed" }
+            }),
+        )
+        .await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["ref"], "2");
+        assert_eq!(error["problem"]["status"], 400);
+        assert_eq!(
+            exchange(&mut socket, json!({ "type": "rename" })).await["problem"]["type"],
+            "type://error.payload.invalid"
+        );
+
+        // Silent clients are disconnected
+        match socket.next().await {
+            Some(Ok(ClientMessage::Close(Some(frame)))) => assert_eq!(frame.reason, "idle timeout"),
+            other => panic!("Expected the idle connection to be closed, got {:?}", other),
+        }
+    }
+}